@@ -0,0 +1,135 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::{Component, Path};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use crate::config::ArtConfig;
+use crate::songentry::{AlbumArt, ArtImage};
+use crate::util;
+
+// file names the game looks for when song.ini has no cover
+pub const ART_NAMES: [&str; 3] = ["album.png", "album.jpg", "album.jpeg"];
+
+// true if a cover named in song.ini stays inside the song folder, on any system
+pub fn is_local_name(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name).components().all(|c| matches!(c, Component::Normal(_)))
+        && name.split(['/', '\\']).all(|c| c != ".." && !c.contains(':'))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtFormat {
+    Webp, // lossless
+    Jpeg,
+}
+
+impl ArtFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArtFormat::Webp => "webp",
+            ArtFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+// size of the image scaled down to fit into a square, never scaled up
+pub fn fit(width: u32, height: u32, size: u32) -> (u32, u32) {
+    if width <= size && height <= size {
+        return (width, height);
+    }
+    let scale = |a: u32, b: u32| ((a as u64 * size as u64 + b as u64 / 2) / b as u64).max(1) as u32;
+    if width >= height {
+        (size, scale(height, width))
+    } else {
+        (scale(width, height), size)
+    }
+}
+
+pub fn encode(img: &DynamicImage, format: ArtFormat, quality: u8) -> Result<Vec<u8>, String> {
+    let mut out = Cursor::new(vec![]);
+    match format {
+        ArtFormat::Webp => DynamicImage::ImageRgba8(img.to_rgba8())
+            .write_to(&mut out, ImageFormat::WebP)
+            .map_err(|e| e.to_string())?,
+        ArtFormat::Jpeg => JpegEncoder::new_with_quality(&mut out, quality)
+            .encode_image(&DynamicImage::ImageRgb8(img.to_rgb8()))
+            .map_err(|e| e.to_string())?,
+    }
+    Ok(out.into_inner())
+}
+
+/*
+   writes the scaled versions of the album art in data into dir
+   files are named after the md5 of the art, <md5>_<size>.<ext>, so songs
+   with the same art share them and existing files are not written again
+   name is the art file in the song folder
+*/
+pub fn make_art(name: &str, data: &[u8], config: &ArtConfig, dir: &Path) -> Result<AlbumArt, String> {
+    let reader = || ImageReader::new(Cursor::new(data)).with_guessed_format().map_err(|e| e.to_string());
+    let (width, height) = reader()?.into_dimensions().map_err(|e| e.to_string())?;
+    let hash = util::checksum_hex(&md5::compute(data).0);
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    let mut sizes = config.sizes.clone();
+    sizes.sort_unstable();
+    sizes.dedup();
+
+    let mut decoded: Option<DynamicImage> = None;
+    let mut images = vec![];
+    for size in sizes {
+        let (w, h) = fit(width, height, size);
+        for format in &config.formats {
+            let file = format!("{}_{}.{}", hash, size, format.extension());
+            let p = dir.join(&file);
+            if !p.exists() {
+                if decoded.is_none() {
+                    decoded = Some(reader()?.decode().map_err(|e| e.to_string())?);
+                }
+                let img = decoded.as_ref().unwrap();
+                let scaled = if (w, h) == (width, height) { img.clone() } else { img.resize_exact(w, h, FilterType::Triangle) };
+                let out = encode(&scaled, *format, config.jpeg_quality)?;
+                util::write_atomic(&p, &out).map_err(|e| format!("{}: {}", p.display(), e))?;
+            }
+            images.push(ArtImage {
+                path: file,
+                format: *format,
+                size,
+                width: w,
+                height: h,
+            });
+        }
+    }
+
+    Ok(AlbumArt {
+        file: String::from(name),
+        width,
+        height,
+        images,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_stay_in_the_song_folder() {
+        for name in ["cover.png", "art/cover.png", "art\\cover.png", "..cover.png"] {
+            assert!(is_local_name(name), "{}", name);
+        }
+        for name in ["", "../cover.png", "art/../../cover.png", "/etc/cover.png", "..\\cover.png", "C:\\cover.png", "."] {
+            assert!(!is_local_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn fits_into_a_square() {
+        assert_eq!(fit(100, 50, 200), (100, 50));
+        assert_eq!(fit(1000, 500, 100), (100, 50));
+        assert_eq!(fit(500, 1000, 100), (50, 100));
+        assert_eq!(fit(10000, 1, 100), (100, 1));
+    }
+}
@@ -0,0 +1,265 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use glob::{MatchOptions, Pattern};
+use serde::{Deserialize, Serialize};
+use crate::art::ArtFormat;
+use crate::intern::TableOrder;
+
+/*
+   scanner configuration, read from a TOML or JSON file, e.g.
+
+   roots = ["songs", "/mnt/extra"]
+   cloud_format = true
+   ignore = ["__MACOSX", ".git", "Backup*"]
+   video_exts = ["mp4", "webm"]
+   playlists = "playlists.json"
+
+   [art]
+   sizes = [64, 256]
+   formats = ["webp", "jpeg"]
+
+   [sprites]
+   size = 64
+   columns = 16
+
+   [output]
+   cache = "songcache.bin"
+   json = "manifest.json"
+   sqlite = "library.db"
+   setlists = "setlists"
+   report = "report.json"
+   table_order = "sorted"
+   art = "art"
+   sprites = "sprites"
+
+   relative paths are resolved from the folder of the config file
+   ignore patterns are globs matched case insensitive against the folder
+   name and the path relative to its root, with / as separator
+*/
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ScanConfig {
+    pub roots: Vec<PathBuf>,
+    pub cloud_format: bool,
+    pub ignore: Vec<String>,
+    pub video_exts: Vec<String>,
+    pub metadata_defaults: [String; 7],
+    pub playlists: Option<PathBuf>,
+    // stop at the first unreadable song instead of skipping it
+    pub strict: bool,
+    // unpack zip archives next to themselves instead of reading them in place
+    pub extract_archives: bool,
+    pub art: ArtConfig,
+    pub sprites: SpriteConfig,
+    pub output: Output,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Output {
+    pub cache: Option<PathBuf>,
+    pub json: Option<PathBuf>,
+    pub plain: bool,
+    pub sqlite: Option<PathBuf>,
+    pub sqlite_update: bool,
+    // folder that gets one .setlist per playlist
+    pub setlists: Option<PathBuf>,
+    // scan report as JSON
+    pub report: Option<PathBuf>,
+    // previous caches to keep as songcache.bin.1, .2 and so on
+    pub backups: usize,
+    // order of the metadata tables in the cache, "insertion" or "sorted"
+    pub table_order: TableOrder,
+    // folder for the scaled album art, none skips the art
+    pub art: Option<PathBuf>,
+    // folder for the album art sprite sheets of every playlist, needs art
+    pub sprites: Option<PathBuf>,
+}
+
+// sheets of size by size cells, columns wide and at most rows high
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpriteConfig {
+    pub size: u32,
+    pub columns: u32,
+    pub rows: u32,
+    pub format: ArtFormat,
+}
+
+// the largest sheet side a WebP image can have
+const MAX_SHEET_SIDE: u32 = 16383;
+
+impl SpriteConfig {
+    pub fn check(&self) -> Result<(), String> {
+        if self.size == 0 || self.columns == 0 || self.rows == 0 {
+            return Err(String::from("sprite size, columns and rows have to be larger than 0"));
+        }
+        let side = |n: u32| n.checked_mul(self.size).filter(|s| *s <= MAX_SHEET_SIDE);
+        if side(self.columns).is_none() || side(self.rows).is_none() {
+            return Err(format!("sprite sheets can not be larger than {0}x{0}", MAX_SHEET_SIDE));
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpriteConfig {
+    fn default() -> Self {
+        SpriteConfig {
+            size: 64,
+            columns: 16,
+            rows: 16,
+            format: ArtFormat::Webp,
+        }
+    }
+}
+
+// scaled versions of the album art, every size in every format
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ArtConfig {
+    pub sizes: Vec<u32>,
+    pub formats: Vec<ArtFormat>,
+    pub jpeg_quality: u8,
+}
+
+impl Default for ArtConfig {
+    fn default() -> Self {
+        ArtConfig {
+            sizes: vec![64, 256],
+            formats: vec![ArtFormat::Webp, ArtFormat::Jpeg],
+            jpeg_quality: 85,
+        }
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            roots: vec![],
+            cloud_format: false,
+            ignore: vec![],
+            video_exts: ["mp4", "avi", "webm", "vp8", "ogv", "mpeg"]
+                .map(String::from)
+                .to_vec(),
+            metadata_defaults: [
+                "Unknown Name",
+                "Unknown Artist",
+                "Unknown Album",
+                "Unknown Genre",
+                "Unknown Year",
+                "Unknown Charter",
+                "Unknown Playlist",
+            ]
+            .map(String::from),
+            playlists: None,
+            strict: false,
+            extract_archives: false,
+            art: ArtConfig::default(),
+            sprites: SpriteConfig::default(),
+            output: Output::default(),
+        }
+    }
+}
+
+// compiled ignore patterns
+pub struct IgnoreSet(Vec<Pattern>);
+
+const IGNORE_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl IgnoreSet {
+    pub fn new(patterns: &[String]) -> Result<IgnoreSet, String> {
+        patterns
+            .iter()
+            .map(|p| Pattern::new(p).map_err(|e| format!("ignore pattern \"{}\": {}", p, e)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(IgnoreSet)
+    }
+
+    // name is the file name, relative the path from the root with / separators
+    pub fn matches(&self, name: &str, relative: &str) -> bool {
+        self.0
+            .iter()
+            .any(|p| p.matches_with(name, IGNORE_OPTIONS) || p.matches_with(relative, IGNORE_OPTIONS))
+    }
+}
+
+impl ScanConfig {
+    // reads a config file, the format is picked from the extension
+    pub fn load(p: &Path) -> Result<ScanConfig, String> {
+        let text = fs::read_to_string(p).map_err(|e| e.to_string())?;
+        let mut config: ScanConfig = match p.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| e.to_string())?,
+            _ => serde_json::from_str(&text).map_err(|e| e.to_string())?,
+        };
+        config.check()?;
+        config.resolve(p.parent().unwrap_or(Path::new("")));
+        Ok(config)
+    }
+
+    // rejects values that can not work, clamps the ones that have a range
+    pub fn check(&mut self) -> Result<(), String> {
+        self.ignore_set()?;
+        if self.art.sizes.contains(&0) {
+            return Err(String::from("art sizes have to be larger than 0"));
+        }
+        self.art.jpeg_quality = self.art.jpeg_quality.clamp(1, 100);
+        self.sprites.check()
+    }
+
+    pub fn ignore_set(&self) -> Result<IgnoreSet, String> {
+        IgnoreSet::new(&self.ignore)
+    }
+
+    // makes every relative path relative to base instead
+    fn resolve(&mut self, base: &Path) {
+        let join = |p: &mut PathBuf| *p = base.join(&*p);
+        self.roots.iter_mut().for_each(join);
+        self.playlists.iter_mut().for_each(join);
+        let o = &mut self.output;
+        let outputs = [&mut o.cache, &mut o.json, &mut o.sqlite, &mut o.setlists, &mut o.report, &mut o.art, &mut o.sprites];
+        for p in outputs.into_iter().flatten() {
+            join(p);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::temp_path;
+
+    fn load(name: &str, text: &str) -> Result<ScanConfig, String> {
+        let p = temp_path(name);
+        fs::write(&p, text).unwrap();
+        let result = ScanConfig::load(&p);
+        let _ = fs::remove_file(&p);
+        result
+    }
+
+    #[test]
+    fn loads_and_resolves_paths() {
+        let config = load("scan.toml", "roots = [\"songs\"]\nignore = [\"Backup*\"]\n[output]\ncache = \"songcache.bin\"\n").unwrap();
+        assert_eq!(config.roots, [std::env::temp_dir().join("songs")]);
+        assert!(config.ignore_set().unwrap().matches("Backup 1", "Backup 1"));
+    }
+
+    #[test]
+    fn rejects_broken_ignore_patterns() {
+        let e = load("scan.json", r#"{"ignore": ["[abc"]}"#).unwrap_err();
+        assert!(e.contains("[abc"), "{}", e);
+
+        // a config that skipped load is reported by the scan instead
+        let config = ScanConfig {
+            roots: vec![temp_path("missing")],
+            ignore: vec![String::from("[abc")],
+            ..Default::default()
+        };
+        let (songs, report) = crate::scanner::scan(&config);
+        assert!(songs.is_empty());
+        assert_eq!(report.errors.len(), 1);
+    }
+}
@@ -0,0 +1,247 @@
+use std::cmp::Ordering;
+use crate::instrument::Instrument;
+use crate::songentry::{SongEntry, METADATA_NAMES};
+use crate::{text, util};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Column {
+    Metadata(usize), // index into SongEntry::metadata
+    Length,
+    Difficulty(Instrument),
+    Charts,
+    Lyrics,
+    Video,
+    Checksum,
+    Path,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SortKey {
+    pub column: Column,
+    pub descending: bool,
+}
+
+// value used for sorting, numbers are compared as numbers
+#[derive(PartialEq, PartialOrd)]
+enum SortValue {
+    Number(i64),
+    Text(String),
+}
+
+impl Column {
+    // parses a column name like "artist", "length" or "diff_drums"
+    pub fn parse(s: &str) -> Option<Column> {
+        let s = s.trim().to_lowercase();
+        if let Some(i) = METADATA_NAMES.iter().position(|n| *n == s) {
+            return Some(Column::Metadata(i));
+        }
+        if let Some(inst) = s.strip_prefix("diff_") {
+            return Instrument::from_name(inst).map(Column::Difficulty);
+        }
+        match s.as_str() {
+            "length" => Some(Column::Length),
+            "charts" => Some(Column::Charts),
+            "lyrics" => Some(Column::Lyrics),
+            "video" => Some(Column::Video),
+            "checksum" => Some(Column::Checksum),
+            "path" => Some(Column::Path),
+            _ => None,
+        }
+    }
+
+    pub fn header(&self) -> String {
+        match self {
+            Column::Metadata(i) => String::from(METADATA_NAMES[*i]),
+            Column::Length => String::from("length"),
+            Column::Difficulty(i) => format!("diff_{}", i.name()),
+            Column::Charts => String::from("charts"),
+            Column::Lyrics => String::from("lyrics"),
+            Column::Video => String::from("video"),
+            Column::Checksum => String::from("checksum"),
+            Column::Path => String::from("path"),
+        }
+    }
+
+    pub fn value(&self, song: &SongEntry) -> String {
+        match self {
+            Column::Metadata(i) => song.metadata[*i].clone(),
+            Column::Length => {
+                if song.song_length <= 0 {
+                    return String::new();
+                }
+                let secs = song.song_length / 1000;
+                format!("{}:{:02}", secs / 60, secs % 60)
+            }
+            Column::Difficulty(i) => {
+                let v = song.intensities.get(*i);
+                if v < 0 {
+                    String::new()
+                } else {
+                    v.to_string()
+                }
+            }
+            Column::Charts => {
+                // e.g. "guitar EMHX, drums X"
+                song.charts
+                    .instruments()
+                    .map(|i| {
+                        let diffs: String = song.charts.difficulties(i).map(|d| d.letter()).collect();
+                        format!("{} {}", i.name(), diffs)
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+            Column::Lyrics => String::from(if song.lyrics { "yes" } else { "no" }),
+            Column::Video => String::from(if song.video_background { "yes" } else { "no" }),
+            Column::Checksum => util::checksum_hex(&song.checksum),
+            Column::Path => song.folder_path.clone(),
+        }
+    }
+
+    fn sort_value(&self, song: &SongEntry) -> SortValue {
+        match self {
+            Column::Length => SortValue::Number(song.song_length as i64),
+            Column::Difficulty(i) => SortValue::Number(song.intensities.get(*i) as i64),
+            Column::Lyrics => SortValue::Number(song.lyrics as i64),
+            Column::Video => SortValue::Number(song.video_background as i64),
+            _ => SortValue::Text(text::sort_key(&self.value(song))),
+        }
+    }
+}
+
+impl SortKey {
+    // parses a sort key, a leading '-' sorts descending
+    pub fn parse(s: &str) -> Option<SortKey> {
+        let s = s.trim();
+        match s.strip_prefix('-') {
+            Some(name) => Column::parse(name).map(|column| SortKey {
+                column,
+                descending: true,
+            }),
+            None => Column::parse(s).map(|column| SortKey {
+                column,
+                descending: false,
+            }),
+        }
+    }
+}
+
+pub fn default_columns() -> Vec<Column> {
+    let mut out: Vec<Column> = (0..METADATA_NAMES.len()).map(Column::Metadata).collect();
+    out.push(Column::Length);
+    out.extend(Instrument::ALL.into_iter().map(Column::Difficulty));
+    out.push(Column::Charts);
+    out.push(Column::Lyrics);
+    out.push(Column::Video);
+    out
+}
+
+// quote a field for the given delimiter
+fn escape(v: &str, delimiter: char) -> String {
+    if delimiter == '\t' {
+        return v.replace(['\t', '\r', '\n'], " ");
+    }
+    if v.contains([delimiter, '"', '\r', '\n']) {
+        format!("\"{}\"", v.replace('"', "\"\""))
+    } else {
+        String::from(v)
+    }
+}
+
+/*
+   builds a CSV (or TSV with a tab delimiter) table of the songs
+   rows are sorted by the sort keys in order, ties keep the input order
+*/
+pub fn write_table(songs: &[SongEntry], columns: &[Column], sort: &[SortKey], delimiter: char) -> String {
+    let rows: Vec<&SongEntry> = songs.iter().collect();
+    write_table_refs(&rows, columns, sort, delimiter)
+}
+
+pub fn write_table_refs(songs: &[&SongEntry], columns: &[Column], sort: &[SortKey], delimiter: char) -> String {
+    let mut rows = songs.to_vec();
+    rows.sort_by(|a, b| {
+        for key in sort {
+            let mut ord = key
+                .column
+                .sort_value(a)
+                .partial_cmp(&key.column.sort_value(b))
+                .unwrap_or(Ordering::Equal);
+            if key.descending {
+                ord = ord.reverse();
+            }
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        Ordering::Equal
+    });
+
+    let sep = delimiter.to_string();
+    let mut out = columns
+        .iter()
+        .map(|c| escape(&c.header(), delimiter))
+        .collect::<Vec<_>>()
+        .join(&sep);
+    out.push_str("\r\n");
+
+    for song in rows {
+        let line = columns
+            .iter()
+            .map(|c| escape(&c.value(song), delimiter))
+            .collect::<Vec<_>>()
+            .join(&sep);
+        out.push_str(&line);
+        out.push_str("\r\n");
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::songs;
+
+    fn columns(names: &[&str]) -> Vec<Column> {
+        names.iter().map(|n| Column::parse(n).unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_columns_and_sort_keys() {
+        assert_eq!(Column::parse(" Artist "), Some(Column::Metadata(1)));
+        assert_eq!(Column::parse("diff_drums"), Some(Column::Difficulty(Instrument::Drums)));
+        assert_eq!(Column::parse("diff_kazoo"), None);
+        assert_eq!(Column::parse("nope"), None);
+        assert_eq!(SortKey::parse("-length"), Some(SortKey { column: Column::Length, descending: true }));
+        for c in default_columns() {
+            assert_eq!(Column::parse(&c.header()), Some(c));
+        }
+    }
+
+    #[test]
+    fn sorts_by_keys_in_order() {
+        let sort = [SortKey::parse("artist").unwrap(), SortKey::parse("-length").unwrap()];
+        let table = write_table(&songs(), &columns(&["name", "length", "charts"]), &sort, ',');
+        assert_eq!(
+            table,
+            "name,length,charts\r\n\
+             Song 4,0:04,guitar X\r\n\
+             Song 2,0:02,guitar X\r\n\
+             Song 0,,guitar X\r\n\
+             Song 3,0:03,guitar X\r\n\
+             Song 1,0:01,guitar X\r\n"
+        );
+    }
+
+    #[test]
+    fn escapes_fields() {
+        let mut song = songs().remove(0);
+        song.metadata[0] = String::from("A \"B\", C");
+        song.metadata[1] = String::from("Line\none\tTab");
+        let cols = columns(&["name", "artist"]);
+        let csv = write_table(std::slice::from_ref(&song), &cols, &[], ',');
+        assert_eq!(csv.lines().nth(1), Some("\"A \"\"B\"\", C\",\"Line"));
+        let tsv = write_table(&[song], &cols, &[], '\t');
+        assert_eq!(tsv, "name\tartist\r\nA \"B\", C\tLine one Tab\r\n");
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use serde_json::Value;
+use crate::songentry::{SongEntry, METADATA_NAMES};
+use crate::util;
+
+#[derive(Serialize, Debug)]
+pub struct SongSummary {
+    pub checksum: String,
+    pub folder_path: String,
+    pub name: String,
+    pub artist: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldChange {
+    pub field: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChangedSong {
+    #[serde(flatten)]
+    pub song: SongSummary,
+    pub changes: Vec<FieldChange>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheDiff {
+    pub added: Vec<SongSummary>,
+    pub removed: Vec<SongSummary>,
+    pub changed: Vec<ChangedSong>,
+}
+
+impl SongSummary {
+    fn new(song: &SongEntry) -> SongSummary {
+        SongSummary {
+            checksum: util::checksum_hex(&song.checksum),
+            folder_path: song.folder_path.clone(),
+            name: song.metadata[0].clone(),
+            artist: song.metadata[1].clone(),
+        }
+    }
+}
+
+// compare two json values and collect every differing leaf
+fn diff_values(field: String, old: &Value, new: &Value, out: &mut Vec<FieldChange>) {
+    match (old, new) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, val) in a {
+                let other = b.get(key).unwrap_or(&Value::Null);
+                diff_values(format!("{}.{}", field, key), val, other, out);
+            }
+        }
+        _ => {
+            if old != new {
+                out.push(FieldChange {
+                    field,
+                    old: old.clone(),
+                    new: new.clone(),
+                });
+            }
+        }
+    }
+}
+
+// field by field comparison of two entries with the same checksum
+fn diff_songs(old: &SongEntry, new: &SongEntry) -> Vec<FieldChange> {
+    let mut out = vec![];
+
+    let old_value = serde_json::to_value(old).unwrap();
+    let new_value = serde_json::to_value(new).unwrap();
+    let (Value::Object(a), Value::Object(b)) = (old_value, new_value) else {
+        return out;
+    };
+
+    for (key, val) in &a {
+        let other = b.get(key).unwrap_or(&Value::Null);
+
+        // name the metadata elements instead of comparing the whole array
+        if key == "metadata" {
+            for (i, name) in METADATA_NAMES.iter().enumerate() {
+                diff_values(String::from(*name), &val[i], &other[i], &mut out);
+            }
+        } else {
+            diff_values(key.clone(), val, other, &mut out);
+        }
+    }
+
+    out
+}
+
+/*
+   compares two song lists using the checksum as the song identity
+   added and changed songs follow the order of the new list,
+   removed songs the order of the old one
+*/
+pub fn diff_caches(old: &[SongEntry], new: &[SongEntry]) -> CacheDiff {
+    let old_map: HashMap<[u8; 16], &SongEntry> = old.iter().map(|s| (s.checksum, s)).collect();
+    let new_map: HashMap<[u8; 16], &SongEntry> = new.iter().map(|s| (s.checksum, s)).collect();
+
+    let mut diff = CacheDiff {
+        added: vec![],
+        removed: vec![],
+        changed: vec![],
+    };
+
+    for song in new {
+        match old_map.get(&song.checksum) {
+            None => diff.added.push(SongSummary::new(song)),
+            Some(old_song) => {
+                let changes = diff_songs(old_song, song);
+                if !changes.is_empty() {
+                    diff.changed.push(ChangedSong {
+                        song: SongSummary::new(song),
+                        changes,
+                    });
+                }
+            }
+        }
+    }
+
+    for song in old {
+        if !new_map.contains_key(&song.checksum) {
+            diff.removed.push(SongSummary::new(song));
+        }
+    }
+
+    diff
+}
+
+impl CacheDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    // human readable summary, one line per song and changed field
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for s in &self.added {
+            out += &format!("+ {} - {} ({})\n", s.artist, s.name, s.folder_path);
+        }
+        for s in &self.removed {
+            out += &format!("- {} - {} ({})\n", s.artist, s.name, s.folder_path);
+        }
+        for c in &self.changed {
+            out += &format!("~ {} - {} ({})\n", c.song.artist, c.song.name, c.song.folder_path);
+            for f in &c.changes {
+                out += &format!("    {}: {} -> {}\n", f.field, f.old, f.new);
+            }
+        }
+        out += &format!(
+            "{} added, {} removed, {} changed\n",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::songs;
+
+    #[test]
+    fn finds_added_removed_and_changed_songs() {
+        let old = songs()[..4].to_vec();
+        let mut new = songs()[1..].to_vec();
+        new[0].metadata[1] = String::from("Renamed");
+        new[0].song_length = 5;
+
+        let diff = diff_caches(&old, &new);
+        let checksums = |s: &[SongSummary]| s.iter().map(|s| s.checksum.clone()).collect::<Vec<_>>();
+        assert_eq!(checksums(&diff.added), [util::checksum_hex(&[5; 16])]);
+        assert_eq!(checksums(&diff.removed), [util::checksum_hex(&[1; 16])]);
+
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].song.name, "Song 1");
+        let fields: Vec<(&str, String, String)> = diff.changed[0]
+            .changes
+            .iter()
+            .map(|c| (c.field.as_str(), c.old.to_string(), c.new.to_string()))
+            .collect();
+        assert!(fields.contains(&("artist", String::from("\"Dragonforce\""), String::from("\"Renamed\""))));
+        assert!(fields.contains(&("song_length", String::from("1000"), String::from("5"))));
+        assert_eq!(fields.len(), 2);
+
+        assert!(diff.summary().ends_with("1 added, 1 removed, 1 changed\n"));
+        assert!(diff_caches(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+use std::path::{Path, PathBuf};
+use std::ffi::OsStr;
+use walkdir::WalkDir;
+use crate::report::Warning;
+use crate::songini::IniDocument;
+
+/*
+   a single edit rule, written as one of
+   artist == "ACDC" -> "AC/DC"
+   artist == "Dragonforce" -> genre = "Power Metal"
+   a missing key compares equal to ""
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub key: String,
+    pub equals: String,
+    pub target: String,
+    pub value: String,
+}
+
+// every edited file with its changes and the files that could not be edited
+#[derive(Debug, Default)]
+pub struct EditReport {
+    pub edited: Vec<(PathBuf, Vec<Change>)>,
+    pub errors: Vec<Warning>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: String,
+}
+
+// strip surrounding double quotes
+fn unquote(s: &str) -> String {
+    let s = s.trim();
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        String::from(&s[1..s.len() - 1])
+    } else {
+        String::from(s)
+    }
+}
+
+impl Rule {
+    pub fn parse(line: &str) -> Result<Rule, String> {
+        let (cond, action) = line
+            .split_once("->")
+            .ok_or_else(|| String::from("expected \"->\""))?;
+        let (key, equals) = cond
+            .split_once("==")
+            .ok_or_else(|| String::from("expected \"==\""))?;
+        let key = key.trim().to_lowercase();
+        if key.is_empty() {
+            return Err(String::from("missing key"));
+        }
+
+        let action = action.trim();
+        let (target, value) = match action.split_once('=') {
+            Some((t, v)) if !action.starts_with('"') => (t.trim().to_lowercase(), unquote(v)),
+            _ => (key.clone(), unquote(action)),
+        };
+        if target.is_empty() {
+            return Err(String::from("missing target key"));
+        }
+
+        Ok(Rule {
+            key,
+            equals: unquote(equals),
+            target,
+            value,
+        })
+    }
+}
+
+// parses a rules file, blank lines and lines starting with # are ignored
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, String> {
+    let mut out = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        out.push(Rule::parse(line).map_err(|e| format!("line {}: {}", i + 1, e))?);
+    }
+    Ok(out)
+}
+
+// applies all rules in order, later rules see the result of earlier ones
+pub fn apply_rules(doc: &mut IniDocument, rules: &[Rule]) -> Vec<Change> {
+    let mut changes = vec![];
+    for rule in rules {
+        if doc.get(&rule.key).unwrap_or("") != rule.equals {
+            continue;
+        }
+        let old = doc.get(&rule.target).map(String::from);
+        if old.as_deref() == Some(rule.value.as_str()) {
+            continue;
+        }
+        doc.set(&rule.target, &rule.value);
+        changes.push(Change {
+            key: rule.target.clone(),
+            old,
+            new: rule.value.clone(),
+        });
+    }
+    changes
+}
+
+impl EditReport {
+    fn error(&mut self, p: &Path, message: String) {
+        log::warn!("{}: {}", p.display(), message);
+        self.errors.push(Warning {
+            path: p.to_string_lossy().to_string(),
+            message,
+        });
+    }
+}
+
+/*
+   applies the rules to every song.ini below the folder
+   files are only written when not doing a dry run, a file that can not
+   be read or written is reported and the others are still edited
+*/
+pub fn edit_library(p: &Path, rules: &[Rule], dry_run: bool) -> EditReport {
+    let mut out = EditReport::default();
+
+    for entry in WalkDir::new(p) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                out.error(e.path().unwrap_or(p), e.to_string());
+                continue;
+            }
+        };
+        let is_ini = entry
+            .path()
+            .file_name()
+            .unwrap_or(OsStr::new(""))
+            .to_string_lossy()
+            .eq_ignore_ascii_case("song.ini");
+        if !is_ini || !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path().to_path_buf();
+        let mut doc = match IniDocument::load(&path) {
+            Ok(doc) => doc,
+            Err(e) => {
+                out.error(&path, e.to_string());
+                continue;
+            }
+        };
+        let changes = apply_rules(&mut doc, rules);
+        if changes.is_empty() {
+            continue;
+        }
+        if !dry_run {
+            if let Err(e) = doc.save(&path) {
+                out.error(&path, e.to_string());
+                continue;
+            }
+        }
+        out.edited.push((path, changes));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Encoding;
+
+    #[test]
+    fn parses_rules() {
+        let rule = Rule::parse(r#"Artist == "ACDC" -> "AC/DC""#).unwrap();
+        assert_eq!(
+            rule,
+            Rule {
+                key: String::from("artist"),
+                equals: String::from("ACDC"),
+                target: String::from("artist"),
+                value: String::from("AC/DC"),
+            }
+        );
+
+        let rule = Rule::parse(r#"artist == "Dragonforce" -> Genre = "Power Metal""#).unwrap();
+        assert_eq!(rule.target, "genre");
+        assert_eq!(rule.value, "Power Metal");
+
+        // a quoted value may contain an equals sign
+        let rule = Rule::parse(r#"name == "" -> "a = b""#).unwrap();
+        assert_eq!(rule.target, "name");
+        assert_eq!(rule.value, "a = b");
+    }
+
+    #[test]
+    fn rejects_broken_rules() {
+        assert!(Rule::parse("artist = ACDC").is_err());
+        assert!(Rule::parse("artist -> AC/DC").is_err());
+        assert!(Rule::parse(" == ACDC -> AC/DC").is_err());
+        assert!(parse_rules("# comment\n\nartist == a -> b\nbroken").unwrap_err().starts_with("line 4"));
+    }
+
+    #[test]
+    fn applies_rules_in_order() {
+        let mut doc = IniDocument::parse("[song]\nartist = ACDC\n", Encoding::Utf8);
+        let rules = parse_rules("artist == ACDC -> AC/DC\nartist == AC/DC -> genre = Rock").unwrap();
+        let changes = apply_rules(&mut doc, &rules);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(doc.get("artist"), Some("AC/DC"));
+        assert_eq!(doc.get("genre"), Some("Rock"));
+    }
+}
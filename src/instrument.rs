@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Instrument {
+    #[serde(rename = "guitar")]
+    Guitar = 0,
+    #[serde(rename = "bass")]
+    Bass = 1,
+    #[serde(rename = "rhythm")]
+    Rhythm = 2,
+    #[serde(rename = "guitar_coop")]
+    GuitarCoop = 3,
+    #[serde(rename = "ghl_guitar")]
+    GHLGuitar = 4,
+    #[serde(rename = "ghl_bass")]
+    GHLBass = 5,
+    #[serde(rename = "drums")]
+    Drums = 6,
+    #[serde(rename = "keys")]
+    Keys = 7,
+    #[serde(rename = "band")]
+    Band = 8,
+    #[serde(rename = "pro_drums")]
+    ProDrums = 9,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Difficulty {
+    #[serde(rename = "easy")]
+    Easy = 0,
+    #[serde(rename = "medium")]
+    Medium = 1,
+    #[serde(rename = "hard")]
+    Hard = 2,
+    #[serde(rename = "expert")]
+    Expert = 3,
+}
+
+impl Instrument {
+    pub const ALL: [Instrument; 10] = [
+        Instrument::Guitar,
+        Instrument::Bass,
+        Instrument::Rhythm,
+        Instrument::GuitarCoop,
+        Instrument::GHLGuitar,
+        Instrument::GHLBass,
+        Instrument::Drums,
+        Instrument::Keys,
+        Instrument::Band,
+        Instrument::ProDrums,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instrument::Guitar => "guitar",
+            Instrument::Bass => "bass",
+            Instrument::Rhythm => "rhythm",
+            Instrument::GuitarCoop => "guitar_coop",
+            Instrument::GHLGuitar => "ghl_guitar",
+            Instrument::GHLBass => "ghl_bass",
+            Instrument::Drums => "drums",
+            Instrument::Keys => "keys",
+            Instrument::Band => "band",
+            Instrument::ProDrums => "pro_drums",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Instrument> {
+        Instrument::ALL.into_iter().find(|i| i.name() == s)
+    }
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 4] = [
+        Difficulty::Easy,
+        Difficulty::Medium,
+        Difficulty::Hard,
+        Difficulty::Expert,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Medium => "medium",
+            Difficulty::Hard => "hard",
+            Difficulty::Expert => "expert",
+        }
+    }
+
+    // single letter used in compact listings, expert is X like in game
+    pub fn letter(&self) -> char {
+        match self {
+            Difficulty::Easy => 'E',
+            Difficulty::Medium => 'M',
+            Difficulty::Hard => 'H',
+            Difficulty::Expert => 'X',
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Difficulty> {
+        Difficulty::ALL.into_iter().find(|d| d.name() == s)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Chart {
+    pub instrument: Instrument,
+    pub difficulty: Difficulty,
+}
+
+// accepts the raw bitmask or a list of charts
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChartsRepr {
+    Bits(i64),
+    List(Vec<Chart>),
+}
+
+/*
+   bitmask of available charts, stored as GStruct6 in the cache
+   each chart is bit (instrument * 4 + difficulty)
+*/
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug, Serialize, Deserialize)]
+#[serde(from = "ChartsRepr", into = "Vec<Chart>")]
+pub struct Charts(pub i64);
+
+impl Charts {
+    fn bit(inst: Instrument, diff: Difficulty) -> i64 {
+        1 << (inst as i64 * Difficulty::ALL.len() as i64 + diff as i64)
+    }
+
+    pub fn has(&self, inst: Instrument, diff: Difficulty) -> bool {
+        self.0 & Charts::bit(inst, diff) != 0
+    }
+
+    pub fn insert(&mut self, inst: Instrument, diff: Difficulty) {
+        self.0 |= Charts::bit(inst, diff);
+    }
+
+    // all available charts, ordered by instrument then difficulty
+    pub fn iter(&self) -> impl Iterator<Item = Chart> + '_ {
+        Instrument::ALL.into_iter().flat_map(move |instrument| {
+            self.difficulties(instrument)
+                .map(move |difficulty| Chart { instrument, difficulty })
+        })
+    }
+
+    // available difficulties for one instrument
+    pub fn difficulties(&self, inst: Instrument) -> impl Iterator<Item = Difficulty> + '_ {
+        Difficulty::ALL.into_iter().filter(move |d| self.has(inst, *d))
+    }
+
+    // instruments with at least one chart
+    pub fn instruments(&self) -> impl Iterator<Item = Instrument> + '_ {
+        Instrument::ALL
+            .into_iter()
+            .filter(move |i| self.difficulties(*i).next().is_some())
+    }
+}
+
+impl From<ChartsRepr> for Charts {
+    fn from(r: ChartsRepr) -> Charts {
+        match r {
+            ChartsRepr::Bits(b) => Charts(b),
+            ChartsRepr::List(l) => {
+                let mut c = Charts::default();
+                for chart in l {
+                    c.insert(chart.instrument, chart.difficulty);
+                }
+                c
+            }
+        }
+    }
+}
+
+impl From<Charts> for Vec<Chart> {
+    fn from(c: Charts) -> Vec<Chart> {
+        c.iter().collect()
+    }
+}
+
+// per instrument difficulty rating from song.ini, -1 if not set
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct Intensities {
+    pub guitar: i8,
+    pub bass: i8,
+    pub rhythm: i8,
+    pub guitar_coop: i8,
+    pub ghl_guitar: i8,
+    pub ghl_bass: i8,
+    pub drums: i8,
+    pub keys: i8,
+    pub band: i8,
+    pub pro_drums: i8,
+}
+
+impl Default for Intensities {
+    fn default() -> Intensities {
+        Intensities::from_array([-1; 10])
+    }
+}
+
+impl Intensities {
+    // array indexed by Instrument
+    pub fn from_array(a: [i8; 10]) -> Intensities {
+        Intensities {
+            guitar: a[0],
+            bass: a[1],
+            rhythm: a[2],
+            guitar_coop: a[3],
+            ghl_guitar: a[4],
+            ghl_bass: a[5],
+            drums: a[6],
+            keys: a[7],
+            band: a[8],
+            pro_drums: a[9],
+        }
+    }
+
+    pub fn get(&self, inst: Instrument) -> i8 {
+        match inst {
+            Instrument::Guitar => self.guitar,
+            Instrument::Bass => self.bass,
+            Instrument::Rhythm => self.rhythm,
+            Instrument::GuitarCoop => self.guitar_coop,
+            Instrument::GHLGuitar => self.ghl_guitar,
+            Instrument::GHLBass => self.ghl_bass,
+            Instrument::Drums => self.drums,
+            Instrument::Keys => self.keys,
+            Instrument::Band => self.band,
+            Instrument::ProDrums => self.pro_drums,
+        }
+    }
+
+    pub fn set(&mut self, inst: Instrument, v: i8) {
+        match inst {
+            Instrument::Guitar => self.guitar = v,
+            Instrument::Bass => self.bass = v,
+            Instrument::Rhythm => self.rhythm = v,
+            Instrument::GuitarCoop => self.guitar_coop = v,
+            Instrument::GHLGuitar => self.ghl_guitar = v,
+            Instrument::GHLBass => self.ghl_bass = v,
+            Instrument::Drums => self.drums = v,
+            Instrument::Keys => self.keys = v,
+            Instrument::Band => self.band = v,
+            Instrument::ProDrums => self.pro_drums = v,
+        }
+    }
+
+    // every instrument with its rating, including unset ones
+    pub fn iter(&self) -> impl Iterator<Item = (Instrument, i8)> + '_ {
+        Instrument::ALL.into_iter().map(move |i| (i, self.get(i)))
+    }
+}
+
+// accepts the old index based array as well as named fields
+impl<'de> Deserialize<'de> for Intensities {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Intensities, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Array([i8; 10]),
+            Named(HashMap<String, i8>),
+        }
+
+        match Repr::deserialize(d)? {
+            Repr::Array(a) => Ok(Intensities::from_array(a)),
+            Repr::Named(m) => {
+                let mut out = Intensities::default();
+                for (k, v) in m {
+                    let inst = Instrument::from_name(&k)
+                        .ok_or_else(|| de::Error::custom(format!("unknown instrument \"{}\"", k)))?;
+                    out.set(inst, v);
+                }
+                Ok(out)
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+// order of the strings in a table
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TableOrder {
+    #[default]
+    Insertion, // as first seen, what the game writes
+    Sorted,    // byte wise, independent of the song order
+}
+
+/*
+   list of unique strings with a hashed lookup of their index
+   used for the metadata tables of a cache, so building them stays
+   linear in the number of songs
+*/
+#[derive(Debug, Clone, Default)]
+pub struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, i32>,
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable::default()
+    }
+
+    // index of s, it is appended if it is not in the table yet
+    pub fn intern(&mut self, s: &str) -> i32 {
+        if let Some(i) = self.indices.get(s) {
+            return *i;
+        }
+        let i = self.strings.len() as i32;
+        self.strings.push(String::from(s));
+        self.indices.insert(String::from(s), i);
+        i
+    }
+
+    pub fn get(&self, s: &str) -> Option<i32> {
+        self.indices.get(s).copied()
+    }
+
+    pub fn strings(&self) -> &[String] {
+        &self.strings
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    // puts the strings in order, indices handed out before are no longer valid
+    pub fn order(&mut self, order: TableOrder) {
+        if order == TableOrder::Sorted {
+            self.strings.sort_unstable();
+            for (i, s) in self.strings.iter().enumerate() {
+                *self.indices.get_mut(s).unwrap() = i as i32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interns_in_insertion_order() {
+        let mut table = StringTable::new();
+        assert_eq!(table.intern("b"), 0);
+        assert_eq!(table.intern("a"), 1);
+        assert_eq!(table.intern("b"), 0);
+        assert_eq!(table.strings(), ["b", "a"]);
+        assert_eq!(table.get("a"), Some(1));
+        assert_eq!(table.get("c"), None);
+    }
+
+    #[test]
+    fn sorts_and_reindexes() {
+        let mut table = StringTable::new();
+        for s in ["Rock", "Metal", "Pop", "Metal"] {
+            table.intern(s);
+        }
+        table.order(TableOrder::Sorted);
+        assert_eq!(table.strings(), ["Metal", "Pop", "Rock"]);
+        assert_eq!(table.get("Rock"), Some(2));
+        assert_eq!(table.intern("Pop"), 1);
+        assert_eq!(table.len(), 3);
+    }
+}
@@ -0,0 +1,92 @@
+use serde::Serialize;
+use crate::songentry::SongEntry;
+use crate::text;
+
+// song with the tag stripped metadata and sort keys next to the original
+#[derive(Serialize)]
+struct PlainSongEntry<'a> {
+    #[serde(flatten)]
+    song: &'a SongEntry,
+    plain_metadata: [String; 7],
+    sort_metadata: [String; 7],
+}
+
+/*
+   serializes a list of songs to a JSON array
+   with plain the stripped metadata and sort keys are added to every song
+*/
+pub fn songs_to_string(songs: &[SongEntry], plain: bool) -> String {
+    if !plain {
+        return serde_json::to_string(songs).unwrap();
+    }
+    let list: Vec<PlainSongEntry> = songs
+        .iter()
+        .map(|song| PlainSongEntry {
+            song,
+            plain_metadata: text::plain_metadata(song),
+            sort_metadata: text::sort_metadata(song),
+        })
+        .collect();
+    serde_json::to_string(&list).unwrap()
+}
+
+/*
+   parses a list of songs from either a JSON array
+   or JSON Lines (one song object per line)
+   missing fields use the SongEntry defaults
+*/
+pub fn songs_from_str(text: &str) -> Result<Vec<SongEntry>, String> {
+    if text.trim_start().starts_with('[') {
+        return serde_json::from_str(text).map_err(|e| e.to_string());
+    }
+
+    let mut out = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let song = serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        out.push(song);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{self, tests::songs};
+
+    fn cache(songs: Vec<SongEntry>) -> Vec<u8> {
+        let mut out = vec![];
+        writer::write_cache(songs, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        for plain in [false, true] {
+            let read = songs_from_str(&songs_to_string(&songs(), plain)).unwrap();
+            assert_eq!(cache(read), cache(songs()));
+        }
+    }
+
+    #[test]
+    fn reads_json_lines() {
+        let lines: Vec<String> = songs().iter().map(|s| serde_json::to_string(s).unwrap()).collect();
+        let read = songs_from_str(&lines.join("\n\n")).unwrap();
+        assert_eq!(cache(read), cache(songs()));
+    }
+
+    #[test]
+    fn fills_in_missing_fields() {
+        let text = r#"{"folder_path": "/a", "checksum": "0102030405060708090a0b0c0d0e0f10"}"#;
+        let song = &songs_from_str(text).unwrap()[0];
+        assert_eq!(song.folder_path, "/a");
+        assert_eq!(song.checksum[15], 0x10);
+        assert_eq!(song.chart_name, SongEntry::default().chart_name);
+
+        let e = songs_from_str("{}\n{\"checksum\": \"xyz\"}\n").unwrap_err();
+        assert!(e.starts_with("line 2:"), "{}", e);
+        assert!(songs_from_str("[{]").is_err());
+    }
+}
@@ -0,0 +1,27 @@
+pub mod art;
+pub mod config;
+pub mod csv;
+pub mod diff;
+pub mod edit;
+pub mod instrument;
+pub mod intern;
+pub mod json;
+pub mod merge;
+pub mod playlist;
+pub mod query;
+pub mod reader;
+pub mod report;
+pub mod scanner;
+pub mod scores;
+pub mod sng;
+pub mod songentry;
+pub mod songini;
+pub mod subset;
+pub mod sprites;
+pub mod sqlite;
+pub mod text;
+pub mod util;
+pub mod watch;
+pub mod writer;
+
+pub const VERSION: i32 = 20220812;
@@ -1,33 +1,637 @@
-mod util;
-mod writer;
-mod songentry;
-mod reader;
-mod scanner;
+use cloud_hero::config::ScanConfig;
+use cloud_hero::intern::TableOrder;
+use cloud_hero::report::ScanReport;
+use cloud_hero::songentry::SongEntry;
+use cloud_hero::{csv, diff, edit, json, merge, playlist, query, reader, scanner, scores, sprites, sqlite, subset, util, watch, writer};
+use std::env;
+use std::fs::{self, File};
+use std::io::{prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
+const USAGE: &str = "usage:
+    cloud-hero scan (<songs folder> <songcache.bin> | --config <scan.toml|scan.json>) [--cloud]
+        [--json <file> [--plain]] [--sqlite <file> [--update]] [--playlists <playlists.json>]
+        [--report <report.json>] [--strict] [--extract] [--backups <n>] [--sort-tables]
+        [--art <folder> [--art-sizes <64,256,..>] [--sprites <folder>]] (--art needs --json)
+    cloud-hero watch (<songs folder> <songcache.bin> | --config <scan.toml|scan.json>) [--debounce <ms>]
+        [same options as scan]
+    cloud-hero read <songcache.bin> <out.json> [--plain]
+    cloud-hero get <songcache.bin> (<position> | <checksum>)...
+    cloud-hero from-json <songs.json|songs.jsonl> <songcache.bin>
+    cloud-hero sqlite <songcache.bin> <library.db> [--update]
+    cloud-hero csv <songcache.bin> <out.csv> [--tsv] [--columns <a,b,..>] [--sort <a,-b,..>]
+    cloud-hero edit <songs folder> <rules file> [--dry-run]
+    cloud-hero query <songcache.bin> <query> [--out <songcache.bin>] [--json <file>]
+    cloud-hero subset <songcache.bin> <out.bin> (--checksums <file> | --paths <file> | --query <query>)
+    cloud-hero setlist <songcache.bin> <out.setlist|.txt|.json|.csv> (--playlists <playlists.json> --name <playlist> | --query <query>)
+    cloud-hero merge <out.bin> <in.bin>...
+    cloud-hero diff <old.bin> <new.bin> [--json <file>]
+    cloud-hero scores <scoredata.bin> [--cache <songcache.bin>] [--json <file>]
+    cloud-hero merge-scores <out.bin> <in.bin>...
 
-const VERSION: i32 = 20220812;
+options for every command:
+    -v, --verbose             list every skipped folder, duplicate and warning
+    -q, --quiet               only log errors
+    --log-level <level>       error, warn, info, debug or trace";
 
-fn main() {
-    println!("Hello, world!");
+// prints log messages to stderr
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level().as_str().to_lowercase(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+fn init_logger(args: &mut Vec<String>) {
+    let mut level = log::LevelFilter::Warn;
+    if take_flag(args, "-v") || take_flag(args, "--verbose") {
+        level = log::LevelFilter::Info;
+    }
+    if take_flag(args, "-q") || take_flag(args, "--quiet") {
+        level = log::LevelFilter::Error;
+    }
+    if let Some(l) = take_option(args, "--log-level") {
+        level = l.parse().unwrap_or_else(|_| exit_with(&format!("unknown log level \"{}\"", l)));
+    }
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(level);
+}
+
+fn exit_with(msg: &str) -> ! {
+    eprintln!("{}", msg);
+    process::exit(1);
+}
+
+// removes a flag from the arguments, returns if it was present
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|a| a == name) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+// removes an option and its value from the arguments
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == name)?;
+    if i + 1 >= args.len() {
+        exit_with(&format!("missing value for {}", name));
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
+fn open_cache(p: &str) -> Vec<SongEntry> {
+    let mut f = BufReader::new(File::open(p).unwrap_or_else(|e| exit_with(&format!("{}: {}", p, e))));
+    reader::read_cache(&mut f).unwrap_or_else(|| exit_with(&format!("{}: invalid cache", p)))
+}
+
+fn write_json(songs: &[SongEntry], p: impl AsRef<Path>, plain: bool) -> Result<(), String> {
+    let p = p.as_ref();
+    let serialized = json::songs_to_string(songs, plain);
+    util::write_atomic(p, serialized.as_bytes()).map_err(|e| format!("{}: {}", p.display(), e))
+}
+
+fn save_cache(songs: Vec<SongEntry>, p: impl AsRef<Path>, backups: usize) {
+    let p = p.as_ref();
+    writer::save_cache(songs, p, backups).unwrap_or_else(|e| exit_with(&format!("{}: {}", p.display(), e)));
+}
+
+fn open_playlists(p: impl AsRef<Path>) -> Vec<playlist::Playlist> {
+    let p = p.as_ref();
+    let text = fs::read_to_string(p).unwrap_or_else(|e| exit_with(&format!("{}: {}", p.display(), e)));
+    playlist::playlists_from_str(&text).unwrap_or_else(|e| exit_with(&format!("{}: {}", p.display(), e)))
+}
+
+fn write_sqlite(songs: &[SongEntry], p: impl AsRef<Path>, update: bool) -> Result<(), String> {
+    let p = p.as_ref();
+    sqlite::export_sqlite(songs, p, update).map_err(|e| format!("{}: {}", p.display(), e))
+}
+
+// writes one .setlist per playlist into a folder
+fn write_setlists(songs: &[SongEntry], playlists: &[playlist::Playlist], dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for list in playlists {
+        let selected: Vec<&SongEntry> = list
+            .select(songs)?
+            .into_iter()
+            .map(|i| &songs[i])
+            .collect();
+        let name = if list.sub_playlist.is_empty() {
+            format!("{}.setlist", list.name)
+        } else {
+            format!("{} - {}.setlist", list.name, list.sub_playlist)
+        };
+        let p = dir.join(name);
+        fs::write(&p, playlist::write_setlist(&selected, playlist::SetlistFormat::CloneHero))
+            .map_err(|e| format!("{}: {}", p.display(), e))?;
+    }
+    Ok(())
+}
+
+/*
+   the config file sets everything, the options given on the command line
+   replace the matching config values
+*/
+fn scan_config(args: &mut Vec<String>) -> ScanConfig {
+    let mut config = match take_option(args, "--config") {
+        Some(p) => ScanConfig::load(Path::new(&p)).unwrap_or_else(|e| exit_with(&format!("{}: {}", p, e))),
+        None => ScanConfig::default(),
+    };
+    if take_flag(args, "--cloud") {
+        config.cloud_format = true;
+    }
+    if let Some(p) = take_option(args, "--json") {
+        config.output.json = Some(p.into());
+    }
+    if take_flag(args, "--plain") {
+        config.output.plain = true;
+    }
+    if let Some(p) = take_option(args, "--sqlite") {
+        config.output.sqlite = Some(p.into());
+    }
+    if take_flag(args, "--update") {
+        config.output.sqlite_update = true;
+    }
+    if let Some(p) = take_option(args, "--playlists") {
+        config.playlists = Some(p.into());
+    }
+    if take_flag(args, "--extract") {
+        config.extract_archives = true;
+    }
+    if take_flag(args, "--strict") {
+        config.strict = true;
+    }
+    if let Some(p) = take_option(args, "--report") {
+        config.output.report = Some(p.into());
+    }
+    if let Some(n) = take_option(args, "--backups") {
+        config.output.backups = n.parse().unwrap_or_else(|_| exit_with(&format!("\"{}\" is not a number", n)));
+    }
+    if let Some(p) = take_option(args, "--art") {
+        config.output.art = Some(p.into());
+    }
+    if let Some(sizes) = take_option(args, "--art-sizes") {
+        config.art.sizes = sizes
+            .split(',')
+            .map(|s| s.trim().parse().unwrap_or_else(|_| exit_with(&format!("\"{}\" is not a number", s))))
+            .collect();
+    }
+    if let Some(p) = take_option(args, "--sprites") {
+        config.output.sprites = Some(p.into());
+    }
+    if take_flag(args, "--sort-tables") {
+        config.output.table_order = TableOrder::Sorted;
+    }
+    match args.len() {
+        0 if !config.roots.is_empty() => {}
+        2 => {
+            config.roots = vec![args[0].clone().into()];
+            config.output.cache = Some(args[1].clone().into());
+        }
+        _ => exit_with(USAGE),
+    }
+    if config.output.cache.is_none() {
+        exit_with("no songcache.bin output set");
+    }
+    if config.output.setlists.is_some() && config.playlists.is_none() {
+        exit_with("setlists need a playlists file");
+    }
+    // the art is only recorded in the JSON manifest
+    if config.output.art.is_some() && config.output.json.is_none() {
+        exit_with("art needs a json output");
+    }
+    if config.output.sprites.is_some() && config.output.art.is_none() {
+        exit_with("sprites need an art folder");
+    }
+    config.check().unwrap_or_else(|e| exit_with(&e));
+    config
+}
+
+fn print_report(report: &ScanReport) {
+    if log::max_level() >= log::LevelFilter::Info {
+        print!("{}", report.summary());
+    } else {
+        print!("{}", report.short_summary());
+    }
+}
+
+fn write_report(report: &ScanReport, p: &Path) -> Result<(), String> {
+    util::write_atomic(p, report.to_json().as_bytes()).map_err(|e| format!("{}: {}", p.display(), e))
+}
+
+// writes the cache and every other output set in the config, stops at the first failure
+fn write_outputs(config: &ScanConfig, mut songs: Vec<SongEntry>, playlists: Option<&[playlist::Playlist]>) -> Result<(), String> {
+    if let Some(playlists) = playlists {
+        playlist::assign_playlists(&mut songs, playlists)?;
+    }
+    let output = &config.output;
+    if let Some(p) = &output.json {
+        write_json(&songs, p, output.plain)?;
+    }
+    if let Some(p) = &output.sqlite {
+        write_sqlite(&songs, p, output.sqlite_update)?;
+    }
+    if let (Some(dir), Some(playlists)) = (&output.setlists, playlists) {
+        write_setlists(&songs, playlists, dir)?;
+    }
+    if let (Some(dir), Some(art)) = (&output.sprites, &output.art) {
+        sprites::write_sprites(&songs, art, &config.art, &config.sprites, dir)?;
+    }
+
+    let p = output.cache.as_ref().unwrap();
+    writer::save_cache_ordered(songs, p, output.backups, output.table_order).map_err(|e| format!("{}: {}", p.display(), e))
+}
+
+fn cmd_scan(mut args: Vec<String>) {
+    let config = scan_config(&mut args);
+    let o = &config.output;
+    let streaming = o.json.is_none() && o.sqlite.is_none() && config.playlists.is_none();
+    if streaming && o.table_order == TableOrder::Insertion {
+        return scan_streaming(&config);
+    }
+    let playlists = config.playlists.as_ref().map(open_playlists);
+
+    let (songs, report) = scanner::scan(&config);
+    print_report(&report);
+    if let Some(p) = &config.output.report {
+        write_report(&report, p).unwrap_or_else(|e| exit_with(&e));
+    }
+    if config.strict && !report.errors.is_empty() {
+        exit_with("scan failed, nothing written");
+    }
+    write_outputs(&config, songs, playlists.as_deref()).unwrap_or_else(|e| exit_with(&e));
+}
+
+/*
+   with only a cache to write every song is encoded as soon as it is found,
+   the entries wait in a spill file next to the cache instead of in memory
+*/
+fn scan_streaming(config: &ScanConfig) {
+    let p = config.output.cache.as_ref().unwrap();
+    let mut spill = p.as_os_str().to_os_string();
+    spill.push(".entries");
+    let fail = |e: std::io::Error| -> ! { exit_with(&format!("{}: {}", p.display(), e)) };
+
+    let mut cache = writer::CacheWriter::with_spill(Path::new(&spill)).unwrap_or_else(|e| fail(e));
+    let mut result = Ok(());
+    let report = scanner::scan_each(config, |s| {
+        if result.is_ok() {
+            result = cache.add(&s.song);
+        }
+    });
+    print_report(&report);
+    // exiting skips drop, which removes the spill file
+    if let Some(p) = &config.output.report {
+        if let Err(e) = write_report(&report, p) {
+            drop(cache);
+            exit_with(&e);
+        }
+    }
+    if config.strict && !report.errors.is_empty() {
+        drop(cache);
+        exit_with("scan failed, nothing written");
+    }
+    if let Err(e) = result {
+        drop(cache);
+        fail(e);
+    }
+    cache.save(p, config.output.backups).unwrap_or_else(|e| fail(e));
+}
+
+/*
+   scans like the scan command, then keeps the outputs up to date
+   every change is followed by a rescan of the changed folders
+*/
+fn cmd_watch(mut args: Vec<String>) {
+    let debounce = take_option(&mut args, "--debounce")
+        .map(|d| d.parse().unwrap_or_else(|_| exit_with(&format!("\"{}\" is not a number", d))))
+        .unwrap_or(2000);
+    let config = scan_config(&mut args);
+    let playlists = config.playlists.as_ref().map(open_playlists);
+
+    let (library, report) = watch::Library::scan(config.clone());
+    print_report(&report);
+    write_outputs(&config, library.song_entries(), playlists.as_deref()).unwrap_or_else(|e| exit_with(&e));
+
+    // a failed write is logged and tried again with the next change
+    let o = &config.output;
+    let skip: Vec<PathBuf> = [&o.cache, &o.json, &o.sqlite, &o.report, &o.setlists, &o.art, &o.sprites]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    let result = watch::watch(library, Duration::from_millis(debounce), &skip, |library, report| {
+        print_report(report);
+        if let Err(e) = o.report.as_ref().map_or(Ok(()), |p| write_report(report, p)) {
+            log::error!("{}", e);
+        }
+        if let Err(e) = write_outputs(&config, library.song_entries(), playlists.as_deref()) {
+            log::error!("{}", e);
+        }
+    });
+    if let Err(e) = result {
+        exit_with(&e);
+    }
+}
+
+fn cmd_read(mut args: Vec<String>) {
+    let plain = take_flag(&mut args, "--plain");
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+    let songs = open_cache(&args[0]);
+    write_json(&songs, &args[1], plain).unwrap_or_else(|e| exit_with(&e));
+}
+
+fn cmd_sqlite(mut args: Vec<String>) {
+    let update = take_flag(&mut args, "--update");
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+    let songs = open_cache(&args[0]);
+    write_sqlite(&songs, &args[1], update).unwrap_or_else(|e| exit_with(&e));
+}
+
+fn cmd_csv(mut args: Vec<String>) {
+    let tsv = take_flag(&mut args, "--tsv");
+    let columns = take_option(&mut args, "--columns");
+    let sort = take_option(&mut args, "--sort").unwrap_or(String::from("artist,name"));
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+
+    let columns = match columns {
+        Some(c) => c
+            .split(',')
+            .map(|n| csv::Column::parse(n).unwrap_or_else(|| exit_with(&format!("unknown column \"{}\"", n))))
+            .collect(),
+        None => csv::default_columns(),
+    };
+    let sort: Vec<csv::SortKey> = sort
+        .split(',')
+        .map(|n| csv::SortKey::parse(n).unwrap_or_else(|| exit_with(&format!("unknown column \"{}\"", n))))
+        .collect();
+
+    let songs = open_cache(&args[0]);
+    let table = csv::write_table(&songs, &columns, &sort, if tsv { '\t' } else { ',' });
+    fs::write(&args[1], table).unwrap();
+}
+
+fn cmd_edit(mut args: Vec<String>) {
+    let dry_run = take_flag(&mut args, "--dry-run");
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+    let text = fs::read_to_string(&args[1]).unwrap_or_else(|e| exit_with(&format!("{}: {}", args[1], e)));
+    let rules = edit::parse_rules(&text).unwrap_or_else(|e| exit_with(&format!("{}: {}", args[1], e)));
+
+    let report = edit::edit_library(Path::new(&args[0]), &rules, dry_run);
+    for (p, changes) in &report.edited {
+        println!("{}", p.to_string_lossy());
+        for c in changes {
+            println!("    {}: {:?} -> {:?}", c.key, c.old.as_deref().unwrap_or(""), c.new);
+        }
+    }
+    if !report.errors.is_empty() {
+        println!("errors:");
+        for e in &report.errors {
+            println!("    {}: {}", e.path, e.message);
+        }
+    }
+    println!(
+        "{} files {}, {} errors",
+        report.edited.len(),
+        if dry_run { "would be edited" } else { "edited" },
+        report.errors.len()
+    );
+}
+
+fn cmd_query(mut args: Vec<String>) {
+    let out = take_option(&mut args, "--out");
+    let json = take_option(&mut args, "--json");
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+    let q = query::Query::parse(&args[1]).unwrap_or_else(|e| exit_with(&format!("invalid query: {}", e)));
+
+    let songs = query::filter(open_cache(&args[0]), &q);
+    for song in &songs {
+        println!("{} - {} ({})", song.metadata[1], song.metadata[0], song.folder_path);
+    }
+    println!("{} songs found", songs.len());
+
+    if let Some(p) = json {
+        write_json(&songs, &p, false).unwrap_or_else(|e| exit_with(&e));
+    }
+    if let Some(p) = out {
+        save_cache(songs, p, 0);
+    }
+}
+
+fn cmd_subset(mut args: Vec<String>) {
+    let checksums = take_option(&mut args, "--checksums");
+    let paths = take_option(&mut args, "--paths");
+    let q = take_option(&mut args, "--query");
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+
+    let read_list = |p: &str| fs::read_to_string(p).unwrap_or_else(|e| exit_with(&format!("{}: {}", p, e)));
+    let sel = match (checksums, paths, q) {
+        (Some(p), None, None) => subset::Selection::checksums_from_str(&read_list(&p))
+            .unwrap_or_else(|e| exit_with(&format!("{}: {}", p, e))),
+        (None, Some(p), None) => subset::Selection::paths_from_str(&read_list(&p)),
+        (None, None, Some(q)) => subset::Selection::Query(
+            query::Query::parse(&q).unwrap_or_else(|e| exit_with(&format!("invalid query: {}", e))),
+        ),
+        _ => exit_with(USAGE),
+    };
+
+    let (songs, missing) = subset::select(open_cache(&args[0]), &sel);
+    for m in &missing {
+        println!("not found: {}", m);
+    }
+    println!("{} songs selected", songs.len());
+
+    save_cache(songs, &args[1], 0);
+}
+
+fn cmd_setlist(mut args: Vec<String>) {
+    let playlists = take_option(&mut args, "--playlists");
+    let name = take_option(&mut args, "--name");
+    let q = take_option(&mut args, "--query");
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+    let ext = Path::new(&args[1]).extension().unwrap_or_default().to_string_lossy();
+    let format = playlist::SetlistFormat::from_extension(&ext)
+        .unwrap_or_else(|| exit_with(&format!("unknown setlist format \"{}\"", ext)));
 
-    let cloud_format = true;
+    let list = match (playlists, name, q) {
+        (Some(p), Some(name), None) => open_playlists(&p)
+            .into_iter()
+            .find(|l| l.name == name)
+            .unwrap_or_else(|| exit_with(&format!("{}: no playlist named \"{}\"", p, name))),
+        (None, None, Some(q)) => playlist::Playlist {
+            query: Some(q),
+            ..Default::default()
+        },
+        _ => exit_with(USAGE),
+    };
 
-    let peth = Path::new("E:\\Spel\\Annat\\Clone Hero\\Songs_");
-    let songs = scanner::scan_folder(&peth, cloud_format);
-    let serialized = serde_json::to_string(&songs).unwrap();
+    let songs = open_cache(&args[0]);
+    let selected: Vec<&SongEntry> = list
+        .select(&songs)
+        .unwrap_or_else(|e| exit_with(&e))
+        .into_iter()
+        .map(|i| &songs[i])
+        .collect();
+    println!("{} songs in setlist", selected.len());
 
-    let mut testout = File::create("stuff/debug.json").unwrap();
-    write!(testout, "{}", serialized).unwrap();
+    fs::write(&args[1], playlist::write_setlist(&selected, format)).unwrap();
+}
+
+// prints single entries as JSON, picked by position or checksum
+fn cmd_get(args: Vec<String>) {
+    if args.len() < 2 {
+        exit_with(USAGE);
+    }
+    let mut cache = reader::IndexedCache::open(Path::new(&args[0]))
+        .unwrap_or_else(|e| exit_with(&format!("{}: {}", args[0], e)));
+
+    let mut songs = vec![];
+    for key in &args[1..] {
+        let song = match (key.parse::<usize>(), util::checksum_from_hex(key)) {
+            (_, Some(checksum)) if key.len() == 32 => cache.get_by_checksum(&checksum),
+            (Ok(i), _) => cache.get(i),
+            _ => exit_with(&format!("\"{}\" is neither a position nor a checksum", key)),
+        };
+        match song.unwrap_or_else(|e| exit_with(&format!("{}: {}", args[0], e))) {
+            Some(song) => songs.push(song),
+            None => exit_with(&format!("{}: no entry {}", args[0], key)),
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(&songs).unwrap());
+}
+
+fn cmd_from_json(args: Vec<String>) {
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+    let text = fs::read_to_string(&args[0]).unwrap_or_else(|e| exit_with(&format!("{}: {}", args[0], e)));
+    let songs = json::songs_from_str(&text).unwrap_or_else(|e| exit_with(&format!("{}: {}", args[0], e)));
+    println!("{} songs imported", songs.len());
+
+    save_cache(songs, &args[1], 0);
+}
+
+fn cmd_merge(args: Vec<String>) {
+    if args.len() < 2 {
+        exit_with(USAGE);
+    }
+    let caches = args[1..].iter().map(|p| open_cache(p)).collect();
+    let songs = merge::merge_caches(caches);
+    println!("{} songs after merge", songs.len());
+
+    save_cache(songs, &args[0], 0);
+}
+
+fn cmd_diff(mut args: Vec<String>) {
+    let json = take_option(&mut args, "--json");
+    if args.len() != 2 {
+        exit_with(USAGE);
+    }
+    let old = open_cache(&args[0]);
+    let new = open_cache(&args[1]);
+    let d = diff::diff_caches(&old, &new);
 
-    let mut f2 = File::create("stuff/songcache2.bin").unwrap();
-    writer::write_cache(songs, &mut f2);
+    match json {
+        Some(p) => {
+            let mut f = File::create(p).unwrap();
+            write!(f, "{}", serde_json::to_string(&d).unwrap()).unwrap();
+        }
+        None => print!("{}", d.summary()),
+    }
+}
+
+fn open_scores(p: &str) -> scores::ScoreData {
+    let f = File::open(p).unwrap_or_else(|e| exit_with(&format!("{}: {}", p, e)));
+    scores::read_scores(&mut BufReader::new(f)).unwrap_or_else(|e| exit_with(&format!("{}: {}", p, e)))
+}
+
+// lists the scores, with the song names when a cache is given
+fn cmd_scores(mut args: Vec<String>) {
+    let cache = take_option(&mut args, "--cache");
+    let json = take_option(&mut args, "--json");
+    if args.len() != 1 {
+        exit_with(USAGE);
+    }
+    let data = open_scores(&args[0]);
+    let songs = cache.map(|p| open_cache(&p)).unwrap_or_default();
+    let scored = scores::join(&data, &songs);
+
+    match json {
+        Some(p) => {
+            let serialized = serde_json::to_string_pretty(&scored).unwrap();
+            util::write_atomic(Path::new(&p), serialized.as_bytes()).unwrap();
+        }
+        None => print!("{}", scores::summary(&scored)),
+    }
+}
+
+fn cmd_merge_scores(args: Vec<String>) {
+    if args.len() < 2 {
+        exit_with(USAGE);
+    }
+    let all = args[1..].iter().map(|p| open_scores(p)).collect();
+    let data = scores::merge_scores(all);
+    println!("{} songs after merge", data.songs.len());
+
+    let mut out = vec![];
+    scores::write_scores(&data, &mut out).unwrap_or_else(|e| exit_with(&e.to_string()));
+    util::write_atomic(Path::new(&args[0]), &out).unwrap_or_else(|e| exit_with(&format!("{}: {}", args[0], e)));
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    init_logger(&mut args);
+    if args.is_empty() {
+        exit_with(USAGE);
+    }
+    let command = args.remove(0);
 
-    /*let mut f = File::open("stuff/songcache2.bin").unwrap();
-    let songs = reader::read_cache(&mut f).unwrap();
-    let serialized = serde_json::to_string(&songs).unwrap();
-    let mut testout = File::create("stuff/debug.json").unwrap();
-    write!(testout, "{}", serialized).unwrap();*/
+    match command.as_str() {
+        "scan" => cmd_scan(args),
+        "watch" => cmd_watch(args),
+        "read" => cmd_read(args),
+        "sqlite" => cmd_sqlite(args),
+        "csv" => cmd_csv(args),
+        "edit" => cmd_edit(args),
+        "query" => cmd_query(args),
+        "subset" => cmd_subset(args),
+        "setlist" => cmd_setlist(args),
+        "get" => cmd_get(args),
+        "from-json" => cmd_from_json(args),
+        "merge" => cmd_merge(args),
+        "diff" => cmd_diff(args),
+        "scores" => cmd_scores(args),
+        "merge-scores" => cmd_merge_scores(args),
+        _ => exit_with(USAGE),
+    }
 }
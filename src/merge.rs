@@ -0,0 +1,42 @@
+use std::collections::HashSet;
+use crate::songentry::SongEntry;
+
+/*
+   merges multiple song lists into one
+   songs are deduplicated by checksum, the first one found is kept
+   the metadata string tables are rebuilt by the writer from the result
+*/
+pub fn merge_caches(caches: Vec<Vec<SongEntry>>) -> Vec<SongEntry> {
+    let mut out = vec![];
+    let mut checksums = HashSet::new();
+
+    for cache in caches {
+        for song in cache {
+            if !checksums.insert(song.checksum) {
+                log::info!("duplicate {}", song.folder_path);
+                continue;
+            }
+            out.push(song);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::songs;
+
+    #[test]
+    fn keeps_the_first_song_per_checksum() {
+        let first = songs()[..3].to_vec();
+        let mut second = songs()[2..].to_vec();
+        second[0].metadata[0] = String::from("Renamed");
+
+        let merged = merge_caches(vec![first, second]);
+        let names: Vec<&str> = merged.iter().map(|s| s.metadata[0].as_str()).collect();
+        assert_eq!(names, ["Song 0", "Song 1", "Song 2", "Song 3", "Song 4"]);
+        assert!(merge_caches(vec![]).is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+use std::path::MAIN_SEPARATOR;
+use serde::{Deserialize, Serialize};
+use crate::csv;
+use crate::query::Query;
+use crate::songentry::SongEntry;
+use crate::util;
+
+/*
+   a virtual playlist, songs are picked by checksum, folder path or query
+   explicit checksums and paths keep their order, query matches follow in
+   song list order
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Playlist {
+    pub name: String,
+    pub sub_playlist: String,
+    pub query: Option<String>,
+    pub checksums: Vec<String>,
+    pub paths: Vec<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SetlistFormat {
+    CloneHero, // .setlist, one checksum per line
+    Text,      // "Artist - Name" per line
+    Json,
+    Csv,
+}
+
+// the playlist as shown by Clone Hero, stored in SongEntry::metadata[6]
+pub fn playlist_metadata(top_level_playlist: &str, sub_playlist: &str) -> String {
+    if sub_playlist.is_empty() {
+        String::from(top_level_playlist)
+    } else {
+        format!("{}{}{}", top_level_playlist, MAIN_SEPARATOR, sub_playlist)
+    }
+}
+
+// parses a JSON list of playlists
+pub fn playlists_from_str(text: &str) -> Result<Vec<Playlist>, String> {
+    let list: Vec<Playlist> = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    for p in &list {
+        p.query()?;
+    }
+    Ok(list)
+}
+
+impl Playlist {
+    fn query(&self) -> Result<Option<Query>, String> {
+        match &self.query {
+            Some(q) => Query::parse(q)
+                .map(Some)
+                .map_err(|e| format!("playlist \"{}\": {}", self.name, e)),
+            None => Ok(None),
+        }
+    }
+
+    // indices of the songs in the playlist, in playlist order
+    pub fn select(&self, songs: &[SongEntry]) -> Result<Vec<usize>, String> {
+        let mut out = vec![];
+        let mut seen = HashSet::new();
+
+        for c in &self.checksums {
+            let checksum = util::checksum_from_hex(c.trim())
+                .ok_or_else(|| format!("playlist \"{}\": invalid checksum \"{}\"", self.name, c))?;
+            if let Some(i) = songs.iter().position(|s| s.checksum == checksum) {
+                if seen.insert(i) {
+                    out.push(i);
+                }
+            }
+        }
+        for p in &self.paths {
+            let p = util::normalize_path(p);
+            if let Some(i) = songs.iter().position(|s| util::normalize_path(&s.folder_path) == p) {
+                if seen.insert(i) {
+                    out.push(i);
+                }
+            }
+        }
+        if let Some(q) = self.query()? {
+            for (i, song) in songs.iter().enumerate() {
+                if q.matches(song) && seen.insert(i) {
+                    out.push(i);
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/*
+   assigns top_level_playlist, sub_playlist and the playlist metadata
+   of every song found in a playlist, without touching the folders
+   the first playlist a song is found in wins and the playlist
+   order is kept through playlist_track
+*/
+pub fn assign_playlists(songs: &mut [SongEntry], playlists: &[Playlist]) -> Result<(), String> {
+    let mut assigned = HashSet::new();
+
+    for p in playlists {
+        let mut track = 0;
+        for i in p.select(songs)? {
+            if !assigned.insert(i) {
+                continue;
+            }
+            track += 1;
+            let song = &mut songs[i];
+            song.top_level_playlist = p.name.to_lowercase();
+            song.sub_playlist = p.sub_playlist.to_lowercase();
+            song.metadata[6] = playlist_metadata(&song.top_level_playlist, &song.sub_playlist);
+            song.playlist_track = track;
+        }
+    }
+
+    Ok(())
+}
+
+impl SetlistFormat {
+    // picks the format from a file extension
+    pub fn from_extension(ext: &str) -> Option<SetlistFormat> {
+        match ext.to_lowercase().as_str() {
+            "setlist" => Some(SetlistFormat::CloneHero),
+            "txt" => Some(SetlistFormat::Text),
+            "json" => Some(SetlistFormat::Json),
+            "csv" => Some(SetlistFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SetlistEntry<'a> {
+    checksum: String,
+    name: &'a str,
+    artist: &'a str,
+    album: &'a str,
+    charter: &'a str,
+    folder_path: &'a str,
+}
+
+// writes the songs as a setlist in the given format
+pub fn write_setlist(songs: &[&SongEntry], format: SetlistFormat) -> String {
+    match format {
+        SetlistFormat::CloneHero => songs
+            .iter()
+            .map(|s| util::checksum_hex(&s.checksum) + "\r\n")
+            .collect(),
+        SetlistFormat::Text => songs
+            .iter()
+            .map(|s| format!("{} - {}\r\n", s.metadata[1], s.metadata[0]))
+            .collect(),
+        SetlistFormat::Json => {
+            let list: Vec<SetlistEntry> = songs
+                .iter()
+                .map(|s| SetlistEntry {
+                    checksum: util::checksum_hex(&s.checksum),
+                    name: &s.metadata[0],
+                    artist: &s.metadata[1],
+                    album: &s.metadata[2],
+                    charter: &s.metadata[5],
+                    folder_path: &s.folder_path,
+                })
+                .collect();
+            serde_json::to_string_pretty(&list).unwrap()
+        }
+        SetlistFormat::Csv => {
+            let columns: Vec<csv::Column> = ["name", "artist", "album", "charter", "length", "checksum"]
+                .iter()
+                .map(|c| csv::Column::parse(c).unwrap())
+                .collect();
+            csv::write_table_refs(songs, &columns, &[], ',')
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::songs;
+
+    fn playlists() -> Vec<Playlist> {
+        let text = format!(
+            r#"[
+                {{"name": "Tournament", "sub_playlist": "Round 1", "checksums": ["{}", "{}"], "paths": ["{}"]}},
+                {{"name": "Metal", "query": "genre:metal"}}
+            ]"#,
+            util::checksum_hex(&[4; 16]),
+            util::checksum_hex(&[2; 16]),
+            songs()[3].folder_path.to_uppercase(),
+        );
+        playlists_from_str(&text).unwrap()
+    }
+
+    #[test]
+    fn selects_in_playlist_order() {
+        let lists = playlists();
+        assert_eq!(lists[0].select(&songs()).unwrap(), [3, 1]);
+        assert_eq!(lists[1].select(&songs()).unwrap(), [1, 4]);
+    }
+
+    #[test]
+    fn first_playlist_wins() {
+        let mut songs = songs();
+        assign_playlists(&mut songs, &playlists()).unwrap();
+        let sep = MAIN_SEPARATOR;
+        assert_eq!(songs[3].metadata[6], format!("tournament{}round 1", sep));
+        assert_eq!((songs[3].playlist_track, songs[1].playlist_track), (1, 2));
+        assert_eq!(songs[1].top_level_playlist, "tournament");
+        assert_eq!((songs[4].metadata[6].as_str(), songs[4].playlist_track), ("metal", 1));
+        assert_eq!(songs[0].metadata[6], "rock");
+    }
+
+    #[test]
+    fn rejects_broken_playlists() {
+        assert!(playlists_from_str(r#"[{"name": "A", "query": "genre:"}]"#).is_err());
+        let list = Playlist {
+            checksums: vec![String::from("xyz")],
+            ..Default::default()
+        };
+        assert!(list.select(&songs()).is_err());
+    }
+
+    #[test]
+    fn writes_setlists() {
+        let songs = songs();
+        let picked = [&songs[1], &songs[0]];
+        assert_eq!(
+            write_setlist(&picked, SetlistFormat::CloneHero),
+            format!("{}\r\n{}\r\n", util::checksum_hex(&[2; 16]), util::checksum_hex(&[1; 16]))
+        );
+        assert_eq!(write_setlist(&picked, SetlistFormat::Text), "Dragonforce - Song 1\r\nACDC - Song 0\r\n");
+        assert!(write_setlist(&picked, SetlistFormat::Csv).starts_with("name,artist,album,charter,length,checksum\r\n"));
+        let json: serde_json::Value = serde_json::from_str(&write_setlist(&picked, SetlistFormat::Json)).unwrap();
+        assert_eq!(json[1]["name"], "Song 0");
+        assert_eq!(SetlistFormat::from_extension("SETLIST"), Some(SetlistFormat::CloneHero));
+        assert_eq!(SetlistFormat::from_extension("xml"), None);
+    }
+}
@@ -0,0 +1,412 @@
+use std::cmp::Ordering;
+use crate::instrument::{Difficulty, Instrument};
+use crate::songentry::{SongEntry, METADATA_NAMES};
+use crate::{text, util};
+
+/*
+   query language for filtering song lists, e.g.
+   artist:"Dragonforce" AND length>300 AND has:drums.expert AND diff_guitar>=5 AND lyrics
+
+   terms:
+   <text field>:<value>     text contains value (name, artist, album, genre, year, charter, playlist, path)
+   <field> <op> <value>     comparison with =, !=, >, >=, <, <=
+                            numbers: length (seconds), diff_<instrument>, album_track, playlist_track
+   has:<instrument>[.<difficulty>]
+   checksum:<hex prefix>
+   lyrics, video, modchart, enc, pro_drums, five_lane
+   terms are combined with AND, OR, NOT and parentheses, AND is implied between terms,
+   a leading - is short for NOT, e.g. -lyrics or -(genre:rock OR genre:metal)
+   text is compared without tags, diacritics and case
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Text(TextField, Op, String),
+    Number(NumberField, Op, f64),
+    Has(Instrument, Option<Difficulty>),
+    Checksum(String),
+    Flag(Flag),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    Contains,
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextField {
+    Metadata(usize), // index into SongEntry::metadata
+    Path,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NumberField {
+    Length,
+    Difficulty(Instrument),
+    AlbumTrack,
+    PlaylistTrack,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Flag {
+    Lyrics,
+    Video,
+    Modchart,
+    Enc,
+    ProDrums,
+    FiveLane,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(Op),
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut out = vec![];
+    let mut chars = s.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            _ if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                out.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                out.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut v = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => v.push(c),
+                        None => return Err(String::from("unterminated string")),
+                    }
+                }
+                out.push(Token::Str(v));
+            }
+            ':' | '=' | '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.peek() == Some(&'=');
+                if eq {
+                    chars.next();
+                }
+                let op = match (c, eq) {
+                    (':', false) => Op::Contains,
+                    ('=', _) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    _ => return Err(format!("unexpected \"{}\"", c)),
+                };
+                out.push(Token::Op(op));
+            }
+            _ => {
+                let mut v = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()\":=!<>".contains(c) {
+                        break;
+                    }
+                    v.push(c);
+                    chars.next();
+                }
+                out.push(Token::Word(v));
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn is_keyword(&self, k: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(k))
+    }
+
+    fn or(&mut self) -> Result<Query, String> {
+        let mut q = self.and()?;
+        while self.is_keyword("or") {
+            self.next();
+            q = Query::Or(Box::new(q), Box::new(self.and()?));
+        }
+        Ok(q)
+    }
+
+    fn and(&mut self) -> Result<Query, String> {
+        let mut q = self.unary()?;
+        loop {
+            if self.is_keyword("and") {
+                self.next();
+            } else if self.peek().is_none() || self.peek() == Some(&Token::Close) || self.is_keyword("or") {
+                return Ok(q);
+            }
+            q = Query::And(Box::new(q), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Query, String> {
+        if self.is_keyword("not") {
+            self.next();
+            return Ok(Query::Not(Box::new(self.unary()?)));
+        }
+        match self.next() {
+            Some(Token::Open) => {
+                let q = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(q),
+                    _ => Err(String::from("expected \")\"")),
+                }
+            }
+            Some(Token::Word(w)) if w == "-" => Ok(Query::Not(Box::new(self.unary()?))),
+            Some(Token::Word(w)) if w.starts_with('-') => Ok(Query::Not(Box::new(self.term(w[1..].to_lowercase())?))),
+            Some(Token::Word(w)) => self.term(w.to_lowercase()),
+            Some(t) => Err(format!("unexpected {:?}", t)),
+            None => Err(String::from("unexpected end of query")),
+        }
+    }
+
+    fn term(&mut self, field: String) -> Result<Query, String> {
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            _ => return flag(&field),
+        };
+        self.next();
+        let value = match self.next() {
+            Some(Token::Word(v)) | Some(Token::Str(v)) => v,
+            _ => return Err(format!("missing value for \"{}\"", field)),
+        };
+
+        if field == "has" {
+            if op != Op::Contains {
+                return Err(String::from("has only supports \":\""));
+            }
+            let value = value.to_lowercase();
+            let (inst, diff) = match value.split_once('.') {
+                Some((i, d)) => (i, Some(d)),
+                None => (value.as_str(), None),
+            };
+            let inst = Instrument::from_name(inst).ok_or_else(|| format!("unknown instrument \"{}\"", inst))?;
+            let diff = match diff {
+                Some(d) => Some(Difficulty::from_name(d).ok_or_else(|| format!("unknown difficulty \"{}\"", d))?),
+                None => None,
+            };
+            return Ok(Query::Has(inst, diff));
+        }
+        if field == "checksum" {
+            return Ok(Query::Checksum(value.to_lowercase()));
+        }
+        if let Some(f) = text_field(&field) {
+            return Ok(Query::Text(f, op, text::sort_key(&value)));
+        }
+        if let Some(f) = number_field(&field) {
+            let v = value
+                .parse::<f64>()
+                .map_err(|_| format!("\"{}\" is not a number", value))?;
+            return Ok(Query::Number(f, if op == Op::Contains { Op::Eq } else { op }, v));
+        }
+        Err(format!("unknown field \"{}\"", field))
+    }
+}
+
+fn text_field(s: &str) -> Option<TextField> {
+    if s == "path" {
+        return Some(TextField::Path);
+    }
+    METADATA_NAMES.iter().position(|n| *n == s).map(TextField::Metadata)
+}
+
+fn number_field(s: &str) -> Option<NumberField> {
+    if let Some(inst) = s.strip_prefix("diff_") {
+        return Instrument::from_name(inst).map(NumberField::Difficulty);
+    }
+    match s {
+        "length" => Some(NumberField::Length),
+        "album_track" => Some(NumberField::AlbumTrack),
+        "playlist_track" => Some(NumberField::PlaylistTrack),
+        _ => None,
+    }
+}
+
+fn flag(s: &str) -> Result<Query, String> {
+    let f = match s {
+        "lyrics" => Flag::Lyrics,
+        "video" => Flag::Video,
+        "modchart" => Flag::Modchart,
+        "enc" => Flag::Enc,
+        "pro_drums" => Flag::ProDrums,
+        "five_lane" => Flag::FiveLane,
+        _ => return Err(format!("unknown flag \"{}\"", s)),
+    };
+    Ok(Query::Flag(f))
+}
+
+fn compare(ord: Option<Ordering>, op: Op) -> bool {
+    let Some(ord) = ord else {
+        return op == Op::Ne;
+    };
+    match op {
+        Op::Contains | Op::Eq => ord == Ordering::Equal,
+        Op::Ne => ord != Ordering::Equal,
+        Op::Gt => ord == Ordering::Greater,
+        Op::Ge => ord != Ordering::Less,
+        Op::Lt => ord == Ordering::Less,
+        Op::Le => ord != Ordering::Greater,
+    }
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Query, String> {
+        let mut p = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let q = p.or()?;
+        if p.pos < p.tokens.len() {
+            return Err(format!("unexpected {:?}", p.tokens[p.pos]));
+        }
+        Ok(q)
+    }
+
+    pub fn matches(&self, song: &SongEntry) -> bool {
+        match self {
+            Query::And(a, b) => a.matches(song) && b.matches(song),
+            Query::Or(a, b) => a.matches(song) || b.matches(song),
+            Query::Not(q) => !q.matches(song),
+            Query::Text(field, op, value) => {
+                let v = match field {
+                    TextField::Metadata(i) => text::sort_key(&song.metadata[*i]),
+                    TextField::Path => text::sort_key(&song.folder_path),
+                };
+                if *op == Op::Contains {
+                    return v.contains(value.as_str());
+                }
+                // compare numbers like years as numbers
+                let ord = match (v.parse::<f64>(), value.parse::<f64>()) {
+                    (Ok(a), Ok(b)) => a.partial_cmp(&b),
+                    (Err(_), Ok(_)) => None,
+                    _ => Some(v.as_str().cmp(value.as_str())),
+                };
+                compare(ord, *op)
+            }
+            Query::Number(field, op, value) => {
+                let v = match field {
+                    NumberField::Length => song.song_length as f64 / 1000.0,
+                    NumberField::Difficulty(i) => song.intensities.get(*i) as f64,
+                    NumberField::AlbumTrack => song.album_track as f64,
+                    NumberField::PlaylistTrack => song.playlist_track as f64,
+                };
+                compare(v.partial_cmp(value), *op)
+            }
+            Query::Has(inst, Some(diff)) => song.has_chart(*inst, *diff),
+            Query::Has(inst, None) => song.charts.difficulties(*inst).next().is_some(),
+            Query::Checksum(prefix) => util::checksum_hex(&song.checksum).starts_with(prefix.as_str()),
+            Query::Flag(f) => match f {
+                Flag::Lyrics => song.lyrics,
+                Flag::Video => song.video_background,
+                Flag::Modchart => song.modchart,
+                Flag::Enc => song.is_enc,
+                Flag::ProDrums => song.force_pro_drums,
+                Flag::FiveLane => song.force_five_lane,
+            },
+        }
+    }
+}
+
+// keeps only the songs matching the query
+pub fn filter(songs: Vec<SongEntry>, q: &Query) -> Vec<SongEntry> {
+    songs.into_iter().filter(|s| q.matches(s)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::songs;
+
+    // names of the test songs matching the query
+    fn names(q: &str) -> Vec<String> {
+        let q = Query::parse(q).unwrap();
+        filter(songs(), &q).into_iter().map(|s| s.metadata[0].clone()).collect()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let a = Query::parse("lyrics OR video AND enc").unwrap();
+        let b = Query::parse("lyrics OR (video AND enc)").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(Query::parse("lyrics enc").unwrap(), Query::parse("lyrics AND enc").unwrap());
+        assert_eq!(names("artist:acdc or artist:dragonforce lyrics"), ["Song 0", "Song 2", "Song 4"]);
+        assert_eq!(names("(artist:acdc or artist:dragonforce) genre:metal"), ["Song 1", "Song 4"]);
+    }
+
+    #[test]
+    fn not_and_minus() {
+        assert_eq!(names("NOT lyrics"), ["Song 1", "Song 3"]);
+        assert_eq!(names("-lyrics"), ["Song 1", "Song 3"]);
+        assert_eq!(names("-(genre:rock OR genre:metal)"), ["Song 2"]);
+        assert_eq!(names("not not lyrics"), names("lyrics"));
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(names("name:\"song 3\""), ["Song 3"]);
+        assert_eq!(names("artist=\"Dragonforce\""), ["Song 1", "Song 3"]);
+        assert!(Query::parse("name:\"song").is_err());
+    }
+
+    #[test]
+    fn numbers_and_text_compare_differently() {
+        assert_eq!(names("length>=3"), ["Song 3", "Song 4"]);
+        assert_eq!(names("diff_guitar<2"), ["Song 0", "Song 1"]);
+        assert_eq!(names("has:guitar.expert length<1"), ["Song 0"]);
+        assert!(names("has:drums").is_empty());
+
+        // years are numbers when both sides are, otherwise text
+        assert_eq!(names("year>=999").len(), 5);
+        assert_eq!(names("year>abc").len(), 0);
+        assert_eq!(names("year!=abc").len(), 5);
+        assert_eq!(names("artist>b"), ["Song 1", "Song 3"]);
+        assert!(Query::parse("length>long").is_err());
+    }
+
+    #[test]
+    fn rejects_broken_queries() {
+        for q in ["", "nope:1", "nope", "diff_kazoo>1", "has:kazoo", "has:guitar.insane", "(lyrics", "lyrics)", ")", "lyrics AND", "NOT", "-", "artist:", "length!5"] {
+            assert!(Query::parse(q).is_err(), "{}", q);
+        }
+    }
+}
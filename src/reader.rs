@@ -1,131 +1,447 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{prelude::*, SeekFrom};
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{self, prelude::*, BufReader, Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap2::Mmap;
+use crate::instrument::{Charts, Intensities};
 use crate::songentry::SongEntry;
+use crate::util::{self, invalid, PREALLOCATE};
 use crate::VERSION;
 
 // .NET 7 bit integer reader
-fn read_7_bit_int(f: &mut File) -> i32 {
+fn read_7_bit_int<R: Read>(f: &mut R) -> io::Result<i32> {
     let mut count = 0;
     let mut shift = 0;
     let mut b: u8;
     loop {
-        b = f.read_u8().unwrap();
-        count |= ((b & 0x7f) << shift) as i32;
+        if shift > 28 {
+            return Err(invalid(String::from("7 bit integer is too long")));
+        }
+        b = f.read_u8()?;
+        count |= ((b & 0x7f) as i32) << shift;
         shift += 7;
-        if !((b & 0x80) != 0) {
+        if (b & 0x80) == 0 {
             break;
         }
     }
-    println!("{}", count);
-    return count;
+    Ok(count)
+}
+
+fn read_string_len<R: Read>(f: &mut R) -> io::Result<u64> {
+    let len = read_7_bit_int(f)?;
+    u64::try_from(len).map_err(|_| invalid(format!("negative string length {}", len)))
 }
 
 // .NET length prefixed string reader
-fn read_string(f: &mut File) -> String {
-    let len = read_7_bit_int(f);
-    let mut buf: Vec<u8> = vec![0; len as usize];
-    f.read(&mut buf).unwrap();
-    return String::from_utf8_lossy(&buf).to_string();
+fn read_string<R: Read>(f: &mut R) -> io::Result<String> {
+    let len = read_string_len(f)?;
+    let buf = util::read_bytes(f, len)?;
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+fn skip_string<R: Read + Seek>(f: &mut R) -> io::Result<()> {
+    let len = read_string_len(f)?;
+    f.seek(SeekFrom::Current(len as i64))?;
+    Ok(())
 }
 
 // .NET bool reader
-fn read_boolean(f: &mut File) -> bool {
-    if f.read_u8().unwrap() == 0 {
-        false
-    } else {
-        true
-    }
+fn read_boolean<R: Read>(f: &mut R) -> io::Result<bool> {
+    Ok(f.read_u8()? != 0)
 }
 
-pub fn read_cache(f: &mut File) -> Option<Vec<SongEntry>> {
+fn read_checksum<R: Read>(f: &mut R) -> io::Result<[u8; 16]> {
+    let mut a: [u8; 16] = [0; 16];
+    f.read_exact(&mut a)?;
+    Ok(a)
+}
+
+// everything in front of the entries
+pub struct Header {
+    pub checksum: [u8; 16],
+    pub lists: [Vec<String>; 7],
+    pub count: usize,
+}
+
+fn read_header<R: Read>(f: &mut R) -> io::Result<Header> {
 
     // verify version
-    let version = f.read_i32::<LittleEndian>().unwrap();
+    let version = f.read_i32::<LittleEndian>()?;
     if version != VERSION {
-        println!("Expected version \"{}\", got \"{}\"", VERSION, version);
-        return None;
+        return Err(invalid(format!("expected version {}, got {}", VERSION, version)));
     }
-    println!("Version: \"{}\"", version);
+    log::debug!("version {}", version);
 
     // get file checksum
-    let mut checksum = [0u8; 16];
-    f.read_exact(&mut checksum).unwrap();
-    println!("Checksum: {:x?}", checksum);
+    let checksum = read_checksum(f)?;
+    log::debug!("checksum {}", util::checksum_hex(&checksum));
 
     // get all key value data
     let mut lists = [vec![], vec![], vec![], vec![], vec![], vec![], vec![]];
     for _ in 0..lists.len() {
-        let list_index = f.read_u8().unwrap() as usize;
-        let num = f.read_i32::<LittleEndian>().unwrap();
+        let list_index = f.read_u8()? as usize;
+        let num = f.read_i32::<LittleEndian>()?;
+        let list = lists
+            .get_mut(list_index)
+            .ok_or_else(|| invalid(format!("unknown metadata list {}", list_index)))?;
         for _ in 0..num {
-            lists[list_index].push(read_string(f));
+            list.push(read_string(f)?);
+        }
+    }
+
+    let count = f.read_i32::<LittleEndian>()?.max(0) as usize;
+    Ok(Header { checksum, lists, count })
+}
+
+fn read_entry<R: Read>(f: &mut R, lists: &[Vec<String>; 7]) -> io::Result<SongEntry> {
+    let text = read_string(f)?;
+    let _ = f.read_i64::<LittleEndian>()?;
+    let _ = f.read_i64::<LittleEndian>()?;
+    let chart_name = read_string(f)?;
+    let is_enc = read_boolean(f)?;
+
+    let mut metadata: [String; 7] = Default::default();
+    for (m, list) in metadata.iter_mut().zip(lists) {
+        let i = f.read_i32::<LittleEndian>()?;
+        *m = list
+            .get(i as usize)
+            .ok_or_else(|| invalid(format!("metadata index {} out of range", i)))?
+            .clone();
+    }
+
+    Ok(SongEntry {
+        folder_path: text,
+
+        chart_name,
+        is_enc,
+        metadata,
+        charts: Charts(f.read_i64::<LittleEndian>()?),
+        lyrics: read_boolean(f)?,
+        intensities: Intensities {
+            band: f.read_i8()?,
+            guitar: f.read_i8()?,
+            rhythm: f.read_i8()?,
+            bass: f.read_i8()?,
+            drums: f.read_i8()?,
+            pro_drums: f.read_i8()?,
+            keys: f.read_i8()?,
+            ghl_guitar: f.read_i8()?,
+            ghl_bass: f.read_i8()?,
+            guitar_coop: 0,
+        },
+        preview_start: f.read_i32::<LittleEndian>()?,
+        icon_name: read_string(f)?,
+        album_track: f.read_i16::<LittleEndian>()?,
+        playlist_track: f.read_i16::<LittleEndian>()?,
+        modchart: read_boolean(f)?,
+        video_background: read_boolean(f)?,
+        force_pro_drums: read_boolean(f)?,
+        force_five_lane: read_boolean(f)?,
+        song_length: f.read_i32::<LittleEndian>()?,
+        date_added: f.read_i64::<LittleEndian>()?, // TODO
+        top_level_playlist: read_string(f)?,
+        sub_playlist: read_string(f)?,
+        checksum: read_checksum(f)?,
+        ini: None,
+        archive: None,
+        art: None,
+    })
+}
+
+// moves past an entry without decoding it, returns its checksum
+fn skip_entry<R: Read + Seek>(f: &mut R) -> io::Result<[u8; 16]> {
+    skip_string(f)?; // folder_path
+    f.seek(SeekFrom::Current(16))?;
+    skip_string(f)?; // chart_name
+    f.seek(SeekFrom::Current(1 + 7 * 4 + 8 + 1 + 9 + 4))?;
+    skip_string(f)?; // icon_name
+    f.seek(SeekFrom::Current(2 + 2 + 4 + 4 + 8))?;
+    skip_string(f)?; // top_level_playlist
+    skip_string(f)?; // sub_playlist
+    read_checksum(f)
+}
+
+pub fn read_cache<R: Read>(f: &mut R) -> Option<Vec<SongEntry>> {
+    let result = (|| {
+        let header = read_header(f)?;
+
+        // loop through all entries
+        let mut out = Vec::with_capacity(header.count.min(PREALLOCATE));
+        for _ in 0..header.count {
+            let song_entry = read_entry(f, &header.lists)?;
+            log::trace!("{} {}", out.len(), song_entry.folder_path);
+            out.push(song_entry);
+        }
+        Ok::<_, io::Error>(out)
+    })();
+
+    match result {
+        Ok(out) => Some(out),
+        Err(e) => {
+            log::warn!("{}", e);
+            None
+        }
+    }
+}
+
+/*
+   byte offset and checksum of every entry in a cache
+   it can be stored next to the cache as <cache>.idx:
+   version i32, header checksum [16], cache length u64, count i32,
+   then offset u64 and checksum [16] per entry
+*/
+pub struct CacheIndex {
+    pub checksum: [u8; 16], // combined checksum from the cache header
+    pub len: u64,           // size of the indexed cache
+    pub entries: Vec<(u64, [u8; 16])>,
+}
+
+impl CacheIndex {
+    // walks over all entries, f has to be right after the header
+    fn build<R: Read + Seek>(f: &mut R, header: &Header, len: u64) -> io::Result<CacheIndex> {
+        let mut entries = Vec::with_capacity(header.count.min(PREALLOCATE));
+        for _ in 0..header.count {
+            let offset = f.stream_position()?;
+            entries.push((offset, skip_entry(f)?));
+        }
+        if f.stream_position()? > len {
+            return Err(invalid(String::from("cache is truncated")));
+        }
+        Ok(CacheIndex {
+            checksum: header.checksum,
+            len,
+            entries,
+        })
+    }
+
+    pub fn sidecar_path(p: &Path) -> PathBuf {
+        let mut name = p.file_name().unwrap_or_default().to_os_string();
+        name.push(".idx");
+        p.with_file_name(name)
+    }
+
+    pub fn load(p: &Path) -> io::Result<CacheIndex> {
+        let mut f = BufReader::new(File::open(p)?);
+        let version = f.read_i32::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(invalid(format!("expected version {}, got {}", VERSION, version)));
+        }
+        let checksum = read_checksum(&mut f)?;
+        let len = f.read_u64::<LittleEndian>()?;
+        let count = f.read_i32::<LittleEndian>()?.max(0) as usize;
+        let mut entries = Vec::with_capacity(count.min(PREALLOCATE));
+        for _ in 0..count {
+            let offset = f.read_u64::<LittleEndian>()?;
+            entries.push((offset, read_checksum(&mut f)?));
+        }
+        Ok(CacheIndex { checksum, len, entries })
+    }
+
+    pub fn save(&self, p: &Path) -> io::Result<()> {
+        let mut f = vec![];
+        f.write_i32::<LittleEndian>(VERSION)?;
+        f.write_all(&self.checksum)?;
+        f.write_u64::<LittleEndian>(self.len)?;
+        f.write_i32::<LittleEndian>(self.entries.len() as i32)?;
+        for (offset, checksum) in &self.entries {
+            f.write_u64::<LittleEndian>(*offset)?;
+            f.write_all(checksum)?;
+        }
+        util::write_atomic(p, &f)
+    }
+
+    // an index only fits the cache it was built from
+    fn matches(&self, header: &Header, len: u64) -> bool {
+        self.checksum == header.checksum && self.len == len && self.entries.len() == header.count
+    }
+}
+
+/*
+   reads single entries on demand instead of the whole cache
+   only the metadata tables and the entry offsets are kept in memory
+*/
+pub struct IndexedCache<R> {
+    f: R,
+    header: Header,
+    index: CacheIndex,
+    positions: HashMap<[u8; 16], usize>,
+}
+
+impl<R: Read + Seek> IndexedCache<R> {
+    // reads the header and builds the index by walking over the entries once
+    pub fn new(mut f: R) -> io::Result<IndexedCache<R>> {
+        let len = f.seek(SeekFrom::End(0))?;
+        f.seek(SeekFrom::Start(0))?;
+        let header = read_header(&mut f)?;
+        let index = CacheIndex::build(&mut f, &header, len)?;
+        Ok(IndexedCache::from_parts(f, header, index))
+    }
+
+    fn from_parts(f: R, header: Header, index: CacheIndex) -> IndexedCache<R> {
+        let mut positions = HashMap::with_capacity(index.entries.len());
+        for (i, (_, checksum)) in index.entries.iter().enumerate() {
+            positions.entry(*checksum).or_insert(i);
+        }
+        IndexedCache {
+            f,
+            header,
+            index,
+            positions,
         }
     }
 
-    // loop through all entries
-    let mut out = vec![];
-    let num = f.read_i32::<LittleEndian>().unwrap();
-
-    for _ in 0..num {
-        let text = read_string(f);
-        let _ = f.read_i64::<LittleEndian>().unwrap();
-        let _ = f.read_i64::<LittleEndian>().unwrap();
-
-        let testtemp = read_string(f);
-        println!("{:?}{:?}", testtemp, f.seek(SeekFrom::Current(0)).unwrap());
-
-        let mut song_entry = SongEntry {
-            folder_path: text,
-
-            chart_name: testtemp,
-            is_enc: read_boolean(f),
-            metadata: [
-                lists[0][f.read_i32::<LittleEndian>().unwrap() as usize].clone(),
-                lists[1][f.read_i32::<LittleEndian>().unwrap() as usize].clone(),
-                lists[2][f.read_i32::<LittleEndian>().unwrap() as usize].clone(),
-                lists[3][f.read_i32::<LittleEndian>().unwrap() as usize].clone(),
-                lists[4][f.read_i32::<LittleEndian>().unwrap() as usize].clone(),
-                lists[5][f.read_i32::<LittleEndian>().unwrap() as usize].clone(),
-                lists[6][f.read_i32::<LittleEndian>().unwrap() as usize].clone(),
-            ],
-            charts: f.read_i64::<LittleEndian>().unwrap(),
-            lyrics: read_boolean(f),
-            intensities: {
-                let mut a: [i8; 10] = [0; 10];
-                a[8] = f.read_i8().unwrap();
-                a[0] = f.read_i8().unwrap();
-                a[2] = f.read_i8().unwrap();
-                a[1] = f.read_i8().unwrap();
-                a[6] = f.read_i8().unwrap();
-                a[9] = f.read_i8().unwrap();
-                a[7] = f.read_i8().unwrap();
-                a[4] = f.read_i8().unwrap();
-                a[5] = f.read_i8().unwrap();
-                a
-            },
-            preview_start: f.read_i32::<LittleEndian>().unwrap(),
-            icon_name: read_string(f),
-            album_track: f.read_i16::<LittleEndian>().unwrap(),
-            playlist_track: f.read_i16::<LittleEndian>().unwrap(),
-            modchart: read_boolean(f),
-            video_background: read_boolean(f),
-            force_pro_drums: read_boolean(f),
-            force_five_lane: read_boolean(f),
-            song_length: f.read_i32::<LittleEndian>().unwrap(),
-            date_added: f.read_i64::<LittleEndian>().unwrap(), // TODO
-            top_level_playlist: read_string(f),
-            sub_playlist: read_string(f),
-            checksum: {
-                let mut a: [u8; 16] = [0; 16];
-                f.read_exact(&mut a).unwrap();
-                a
-            },
+    pub fn len(&self) -> usize {
+        self.index.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.entries.is_empty()
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn index(&self) -> &CacheIndex {
+        &self.index
+    }
+
+    // position of the first entry with the checksum
+    pub fn position(&self, checksum: &[u8; 16]) -> Option<usize> {
+        self.positions.get(checksum).copied()
+    }
+
+    pub fn get(&mut self, i: usize) -> io::Result<Option<SongEntry>> {
+        let Some((offset, _)) = self.index.entries.get(i) else {
+            return Ok(None);
         };
-        println!("{:?} {:?}", out.len(), song_entry.folder_path);
-        out.push(song_entry);
+        self.f.seek(SeekFrom::Start(*offset))?;
+        read_entry(&mut self.f, &self.header.lists).map(Some)
     }
 
-    Some(out)
+    pub fn get_by_checksum(&mut self, checksum: &[u8; 16]) -> io::Result<Option<SongEntry>> {
+        match self.position(checksum) {
+            Some(i) => self.get(i),
+            None => Ok(None),
+        }
+    }
+}
 
-}
\ No newline at end of file
+impl IndexedCache<Cursor<Mmap>> {
+    /*
+       maps the cache into memory and uses the <cache>.idx sidecar if it
+       still fits the cache, otherwise the index is built and saved again
+    */
+    pub fn open(p: &Path) -> io::Result<IndexedCache<Cursor<Mmap>>> {
+        let file = File::open(p)?;
+        // the cache is only replaced by renaming over it, never changed in place
+        let map = unsafe { Mmap::map(&file)? };
+        let len = map.len() as u64;
+        let mut f = Cursor::new(map);
+        let header = read_header(&mut f)?;
+
+        let sidecar = CacheIndex::sidecar_path(p);
+        let index = match CacheIndex::load(&sidecar) {
+            Ok(index) if index.matches(&header, len) => index,
+            _ => {
+                let index = CacheIndex::build(&mut f, &header, len)?;
+                if let Err(e) = index.save(&sidecar) {
+                    log::debug!("{}: {}", sidecar.display(), e);
+                }
+                index
+            }
+        };
+        Ok(IndexedCache::from_parts(f, header, index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::{self, tests::{songs, temp_path}};
+
+    fn cache() -> Vec<u8> {
+        let mut out = vec![];
+        writer::write_cache(songs(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trips_a_cache() {
+        let data = cache();
+        let read = read_cache(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(read.len(), 5);
+        assert_eq!(read[3].folder_path, songs()[3].folder_path);
+        assert_eq!(read[3].chart_name, songs()[3].chart_name);
+
+        let mut again = vec![];
+        writer::write_cache(read, &mut again).unwrap();
+        assert_eq!(again, data);
+    }
+
+    #[test]
+    fn rejects_broken_caches() {
+        let data = cache();
+        assert!(read_cache(&mut Cursor::new(&data[..data.len() - 1])).is_none());
+        let mut wrong_version = data.clone();
+        wrong_version[0] ^= 1;
+        assert!(read_cache(&mut Cursor::new(&wrong_version)).is_none());
+        assert!(IndexedCache::new(Cursor::new(&data[..data.len() - 1])).is_err());
+
+        // the first string of the header, after version, checksum, list index and count
+        let first_string = 4 + 16 + 1 + 4;
+        let lengths: [&[u8]; 3] = [
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0x01], // longer than 5 bytes
+            &[0xff, 0xff, 0xff, 0xff, 0x0f],       // -1
+            &[0xff, 0xff, 0xff, 0xff, 0x07],       // i32::MAX, more than the input
+        ];
+        for len in lengths {
+            let mut broken = data.clone();
+            broken[first_string..first_string + len.len()].copy_from_slice(len);
+            assert!(read_cache(&mut Cursor::new(&broken)).is_none());
+            assert!(IndexedCache::new(Cursor::new(&broken)).is_err());
+            assert!(read_string(&mut Cursor::new(len)).is_err());
+        }
+    }
+
+    #[test]
+    fn skip_entry_ends_where_the_next_entry_starts() {
+        let data = cache();
+        let cache = IndexedCache::new(Cursor::new(&data)).unwrap();
+        let offsets: Vec<u64> = cache.index().entries.iter().map(|(o, _)| *o).collect();
+
+        let mut f = Cursor::new(&data);
+        for (i, offset) in offsets.iter().enumerate() {
+            f.seek(SeekFrom::Start(*offset)).unwrap();
+            read_entry(&mut f, &cache.header().lists).unwrap();
+            let end = offsets.get(i + 1).copied().unwrap_or(data.len() as u64);
+            assert_eq!(f.stream_position().unwrap(), end);
+        }
+    }
+
+    #[test]
+    fn gets_entries_by_position_and_checksum() {
+        let mut cache = IndexedCache::new(Cursor::new(cache())).unwrap();
+        assert_eq!(cache.len(), 5);
+        assert_eq!(cache.get(2).unwrap().unwrap().metadata[0], "Song 2");
+        assert!(cache.get(5).unwrap().is_none());
+        assert_eq!(cache.position(&[4; 16]), Some(3));
+        assert_eq!(cache.get_by_checksum(&[4; 16]).unwrap().unwrap().metadata[0], "Song 3");
+        assert!(cache.get_by_checksum(&[9; 16]).unwrap().is_none());
+    }
+
+    #[test]
+    fn sidecar_index_round_trips() {
+        let data = cache();
+        let cache = IndexedCache::new(Cursor::new(&data)).unwrap();
+        let p = temp_path("cache.idx");
+        cache.index().save(&p).unwrap();
+        let loaded = CacheIndex::load(&p).unwrap();
+        let _ = std::fs::remove_file(&p);
+
+        assert_eq!(loaded.entries, cache.index().entries);
+        assert!(loaded.matches(cache.header(), data.len() as u64));
+        assert!(!loaded.matches(cache.header(), data.len() as u64 + 1));
+    }
+}
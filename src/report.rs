@@ -0,0 +1,172 @@
+use std::path::Path;
+use serde::Serialize;
+
+/*
+   everything noteworthy that happened during a scan
+   serialized as JSON for tooling, summary() is the human readable version
+*/
+#[derive(Serialize, Default, Debug)]
+pub struct ScanReport {
+    pub songs: usize,
+    pub folders: usize,
+    pub duration_secs: f64,
+    pub skipped: Vec<Skipped>,
+    pub duplicates: Vec<Duplicate>,
+    pub warnings: Vec<Warning>,
+    // folders that could not be read, these songs are missing from the cache
+    pub errors: Vec<Warning>,
+    // songs indexed without a valid song.ini, using the chart header instead
+    pub fallback_metadata: Vec<String>,
+    // encrypted or packaged songs, clients can not download them as plain files
+    pub unservable: Vec<Warning>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    Ignored, // matched an ignore pattern
+    NoNotes, // has a song.ini but no notes.mid or notes.chart
+}
+
+#[derive(Serialize, Debug)]
+pub struct Skipped {
+    pub path: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Duplicate {
+    pub path: String,
+    pub original: String, // the folder that was kept
+    pub checksum: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Warning {
+    pub path: String,
+    pub message: String,
+}
+
+impl SkipReason {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            SkipReason::Ignored => "ignored",
+            SkipReason::NoNotes => "no notes file",
+        }
+    }
+}
+
+impl ScanReport {
+    pub fn skip(&mut self, p: &Path, reason: SkipReason) {
+        log::debug!("skipped {} ({})", p.display(), reason.describe());
+        self.skipped.push(Skipped {
+            path: p.to_string_lossy().to_string(),
+            reason,
+        });
+    }
+
+    pub fn duplicate(&mut self, p: &Path, original: &str, checksum: String) {
+        log::debug!("duplicate {} of {}", p.display(), original);
+        self.duplicates.push(Duplicate {
+            path: p.to_string_lossy().to_string(),
+            original: String::from(original),
+            checksum,
+        });
+    }
+
+    pub fn warn(&mut self, p: &Path, message: String) {
+        log::debug!("{}: {}", p.display(), message);
+        self.warnings.push(Warning {
+            path: p.to_string_lossy().to_string(),
+            message,
+        });
+    }
+
+    pub fn error(&mut self, p: &Path, message: String) {
+        log::warn!("{}: {}", p.display(), message);
+        self.errors.push(Warning {
+            path: p.to_string_lossy().to_string(),
+            message,
+        });
+    }
+
+    pub fn unservable(&mut self, p: &Path, reason: String) {
+        log::debug!("{} can not be served ({})", p.display(), reason);
+        self.unservable.push(Warning {
+            path: p.to_string_lossy().to_string(),
+            message: reason,
+        });
+    }
+
+    // adds the results of another scan, the song count is left to the caller
+    pub fn merge(&mut self, other: ScanReport) {
+        self.folders += other.folders;
+        self.duration_secs += other.duration_secs;
+        self.skipped.extend(other.skipped);
+        self.duplicates.extend(other.duplicates);
+        self.warnings.extend(other.warnings);
+        self.errors.extend(other.errors);
+        self.fallback_metadata.extend(other.fallback_metadata);
+        self.unservable.extend(other.unservable);
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // single line with the counts
+    pub fn short_summary(&self) -> String {
+        format!(
+            "{} songs in {} folders, {} skipped, {} duplicates, {} warnings, {} errors, {:.2}s\n",
+            self.songs,
+            self.folders,
+            self.skipped.len(),
+            self.duplicates.len(),
+            self.warnings.len(),
+            self.errors.len(),
+            self.duration_secs
+        )
+    }
+
+    // the counts followed by every skipped folder, duplicate, unservable song, warning and error
+    pub fn summary(&self) -> String {
+        let mut out = self.short_summary();
+        if !self.skipped.is_empty() {
+            out += "skipped:\n";
+            for s in &self.skipped {
+                out += &format!("    {} ({})\n", s.path, s.reason.describe());
+            }
+        }
+        if !self.duplicates.is_empty() {
+            out += "duplicates:\n";
+            for d in &self.duplicates {
+                out += &format!("    {} (same as {})\n", d.path, d.original);
+            }
+        }
+        if !self.fallback_metadata.is_empty() {
+            out += "without a valid song.ini:\n";
+            for p in &self.fallback_metadata {
+                out += &format!("    {}\n", p);
+            }
+        }
+        if !self.unservable.is_empty() {
+            out += "not servable as plain files:\n";
+            for u in &self.unservable {
+                out += &format!("    {} ({})\n", u.path, u.message);
+            }
+        }
+        if !self.warnings.is_empty() {
+            out += "warnings:\n";
+            for w in &self.warnings {
+                out += &format!("    {}: {}\n", w.path, w.message);
+            }
+        }
+        if !self.errors.is_empty() {
+            out += "errors:\n";
+            for e in &self.errors {
+                out += &format!("    {}: {}\n", e.path, e.message);
+            }
+        }
+        out
+    }
+}
@@ -1,7 +1,18 @@
-use crate::{songentry::SongEntry, util};
+use crate::art::{self, ART_NAMES};
+use crate::config::{IgnoreSet, ScanConfig};
+use crate::instrument::{Difficulty, Instrument};
+use crate::playlist;
+use crate::songini::SongIni;
+use crate::sng::SngPackage;
+use crate::songentry::{ArchiveSource, SongEntry};
+use crate::util;
+use byteorder::ReadBytesExt;
 use midly::{MetaMessage, MidiMessage, Smf, TrackEventKind};
+use crate::report::{ScanReport, SkipReason};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::prelude::*;
+use std::io::{self, BufReader, Read};
+use std::time::Instant;
 use std::{
     ffi::OsStr,
     fs,
@@ -9,149 +20,59 @@ use std::{
 };
 use walkdir::WalkDir;
 
-const VIDEO_EXTS: [&str; 6] = ["mp4", "avi", "webm", "vp8", "ogv", "mpeg"];
-const METADATA_DEFAULTS: [&str; 7] = [
-    "Unknown Name",
-    "Unknown Artist",
-    "Unknown Album",
-    "Unknown Genre",
-    "Unknown Year",
-    "Unknown Charter",
-    "Unknown Playlist",
-];
-
-#[derive(Copy, Clone, PartialEq)]
-enum Instrument {
-    None = -1,
-    Guitar = 0,
-    Bass = 1,
-    Rhythm = 2,
-    GuitarCoop = 3,
-    GHLGuitar = 4,
-    GHLBass = 5,
-    Drums = 6,
-    Keys = 7,
-    Band = 8,
-    ProDrums = 9,
-}
-
-fn ini_get_bool(v: &str) -> Option<bool> {
-    match v.to_lowercase().as_str() {
-        "true" | "yes" | "t" | "y" | "1" | "on" => Some(true),
-        "false" | "no" | "f" | "n" | "0" | "off" => Some(false),
-        _ => None,
-    }
-}
-
-fn read_ini(song: &mut SongEntry, p: &PathBuf) -> bool {
-    let mut flag = false;
-
-    let raw_text = util::string_from_file(&p);
-    let mut section = String::new();
-
-    for line in raw_text.lines() {
-        let line = line.trim();
-
-        if line.starts_with('[') {
-            let end_pos = line.find(']').unwrap_or(line.len() - 1);
-            section = line.get(1..end_pos).unwrap().to_lowercase();
-            continue;
-        }
-
-        // split key and value, ignore errors
-        let arr_ = line.split_once('=');
-        if arr_.is_none() {
-            continue;
-        }
-        let arr = arr_.unwrap();
-
-        // properly format key and value
-        let key = arr.0.trim().to_lowercase();
-        let val = arr.1.trim().to_string();
-
-        if section == "song" {
-            flag = true;
-
-            match key.as_str() {
-                "name" => song.metadata[0] = val,
-                "artist" => song.metadata[1] = val,
-                "album" => song.metadata[2] = val,
-                "genre" => song.metadata[3] = val,
-                "year" => song.metadata[4] = val,
-                "charter" | "frets" => song.metadata[5] = val,
-
-                "diff_band" => song.intensities[8] = val.parse::<i8>().unwrap_or(-1),
-                "diff_guitar" => song.intensities[0] = val.parse::<i8>().unwrap_or(-1),
-                "diff_rhythm" => song.intensities[2] = val.parse::<i8>().unwrap_or(-1),
-                "diff_bass" => song.intensities[1] = val.parse::<i8>().unwrap_or(-1),
-                "diff_drums" => song.intensities[6] = val.parse::<i8>().unwrap_or(-1),
-                "diff_drums_real" => song.intensities[9] = val.parse::<i8>().unwrap_or(-1),
-                "diff_keys" => song.intensities[7] = val.parse::<i8>().unwrap_or(-1),
-                "diff_guitarghl" => song.intensities[4] = val.parse::<i8>().unwrap_or(-1),
-                "diff_bassghl" => song.intensities[5] = val.parse::<i8>().unwrap_or(-1),
-
-                "preview_start_time" => song.preview_start = val.parse::<i32>().unwrap_or(-1),
-                "icon" => song.icon_name = val.to_lowercase(),
-                "playlist_track" => song.playlist_track = val.parse::<i16>().unwrap_or(16000),
-                "modchart" => song.modchart = ini_get_bool(&val).unwrap_or(false),
-                "song_length" => song.song_length = val.parse::<i32>().unwrap_or(0),
-                "pro_drums" => song.force_pro_drums = ini_get_bool(&val).unwrap_or(false),
-                "five_lane_drums" => song.force_five_lane = ini_get_bool(&val).unwrap_or(false),
-                "playlist" => song.top_level_playlist = val.to_lowercase(),
-                "sub_playlist" => song.sub_playlist = val.to_lowercase(),
-
-                "album_track" | "track" => song.album_track = val.parse::<i16>().unwrap_or(16000),
-                _ => {}
-            }
-
-            // fix intensities
-            song.intensities[3] = 0;
-            if song.intensities[9] == -1 {
-                song.intensities[9] = song.intensities[6];
-            }
+// returns false if the file has no [song] section
+fn read_ini(song: &mut SongEntry, buf: &[u8]) -> bool {
+    let raw_text = util::string_from_bytes(buf);
+    match SongIni::parse(&raw_text) {
+        Some(ini) => {
+            ini.apply(song);
+            song.ini = Some(ini);
+            true
         }
+        None => false,
     }
-
-    flag
 }
 
-fn apply_charts(song: &mut SongEntry, inst: Instrument, flag: bool, diff: i64) {
+fn apply_charts(song: &mut SongEntry, inst: Instrument, flag: bool, diff: Difficulty) {
     if inst == Instrument::Drums && (flag || song.force_pro_drums || song.force_five_lane) {
-        let num = 1 << (Instrument::ProDrums as i64 * Instrument::GHLGuitar as i64 + diff);
-        if !((song.charts & num) == num) {
-            song.charts |= num;
-        }
-    }
-    let num = 1 << (inst as i64 * Instrument::GHLGuitar as i64 + diff);
-    if !((song.charts & num) == num) {
-        song.charts |= num;
+        song.charts.insert(Instrument::ProDrums, diff);
     }
+    song.charts.insert(inst, diff);
 }
 
-fn read_midi(song: &mut SongEntry, buf: &[u8]) {
-    let smf = Smf::parse(buf).unwrap();
+fn read_midi(song: &mut SongEntry, buf: &[u8], full: bool, warnings: &mut Vec<String>) -> Result<(), String> {
+    let smf = Smf::parse(buf).map_err(|e| e.to_string())?;
     for i in 0..smf.tracks.len() {
-        let mut inst = Instrument::None;
+        let mut inst = None;
         let mut diff = [false; 4];
         let mut flag = false;
 
         for j in 0..smf.tracks[i].len() {
             match smf.tracks[i][j].kind {
                 TrackEventKind::Meta(MetaMessage::TrackName(m)) => {
+                    // the first track is named after the song
+                    if full && i == 0 {
+                        let name = String::from_utf8_lossy(m).trim().to_string();
+                        if name != "TEMPO TRACK" && !name.is_empty() && name != "midi_export" {
+                            song.metadata[0] = name;
+                        }
+                        break;
+                    }
+
                     let str = String::from_utf8_lossy(m).to_lowercase();
                     match str.as_str() {
                         "part vocals" => {
                             song.lyrics = true;
                             break;
                         }
-                        "part guitar" | "t1 gems" => inst = Instrument::Guitar,
-                        "part bass" => inst = Instrument::Bass,
-                        "part rhythm" => inst = Instrument::Rhythm,
-                        "part guitar coop" => inst = Instrument::GuitarCoop,
-                        "part guitar ghl" => inst = Instrument::GHLGuitar,
-                        "part bass ghl" => inst = Instrument::GHLBass,
-                        "part drums" | "part drum" => inst = Instrument::Drums,
-                        "part keys" => inst = Instrument::Keys,
+                        "part guitar" | "t1 gems" => inst = Some(Instrument::Guitar),
+                        "part bass" => inst = Some(Instrument::Bass),
+                        "part rhythm" => inst = Some(Instrument::Rhythm),
+                        "part guitar coop" => inst = Some(Instrument::GuitarCoop),
+                        "part guitar ghl" => inst = Some(Instrument::GHLGuitar),
+                        "part bass ghl" => inst = Some(Instrument::GHLBass),
+                        "part drums" | "part drum" => inst = Some(Instrument::Drums),
+                        "part keys" => inst = Some(Instrument::Keys),
                         _ => {
                             break;
                         }
@@ -177,26 +98,35 @@ fn read_midi(song: &mut SongEntry, buf: &[u8]) {
             };
         }
 
-        if inst != Instrument::None && diff != [false, false, false, false] {
-            if diff != [true, true, true, true] {
-                println!("{:?}: {:?}, {:?}", inst as i64, diff, song.folder_path);
-            }
-            for d in 0..diff.len() {
-                if diff[d] {
-                    apply_charts(song, inst, flag, d as i64);
+        if let Some(inst) = inst {
+            if diff != [false, false, false, false] {
+                if diff != [true, true, true, true] {
+                    let missing: Vec<&str> = Difficulty::ALL
+                        .into_iter()
+                        .zip(diff)
+                        .filter(|(_, present)| !present)
+                        .map(|(d, _)| d.name())
+                        .collect();
+                    warnings.push(format!("{} is missing {}", inst.name(), missing.join(", ")));
+                }
+                for (d, present) in Difficulty::ALL.into_iter().zip(diff) {
+                    if present {
+                        apply_charts(song, inst, flag, d);
+                    }
                 }
             }
         }
     }
+    Ok(())
 }
 
-const DIFF: [&str; 4] = ["easy", "medium", "hard", "expert"];
-fn read_chart(song: &mut SongEntry, buf: &[u8], full: bool) {
+fn read_chart(song: &mut SongEntry, buf: &[u8], full: bool, warnings: &mut Vec<String>) {
     let raw_text = util::string_from_bytes(buf);
+    let mut sections = 0;
 
     let mut section = String::new();
-    let mut inst = Instrument::None;
-    let mut diff = -1i64;
+    let mut inst = None;
+    let mut diff = None;
     let mut notes_flag = false;
     let mut drums_flag = false;
 
@@ -210,24 +140,25 @@ fn read_chart(song: &mut SongEntry, buf: &[u8], full: bool) {
 
         // on new section
         if line.starts_with('[') {
-            section = line.get(1..line.len() - 1).unwrap().to_lowercase();
+            sections += 1;
+            section = line.get(1..line.len() - 1).unwrap_or("").to_lowercase();
 
             // get inst and diff berforehand
-            for d in 0..DIFF.len() {
-                if section.starts_with(DIFF[d]) {
-                    diff = d as i64;
+            for d in Difficulty::ALL {
+                if section.starts_with(d.name()) {
+                    diff = Some(d);
                     inst = {
-                        match section.replace(DIFF[d], "").as_str() {
-                            "single" => Instrument::Guitar,
-                            "doublebass" => Instrument::Bass,
-                            "doublerhythm" => Instrument::Rhythm,
-                            "doubleguitar" => Instrument::GuitarCoop,
-                            "ghlguitar" => Instrument::GHLGuitar,
-                            "ghlbass" => Instrument::GHLBass,
-                            "drums" => Instrument::Drums,
-                            "keyboard" => Instrument::Keys,
-                            "band" => Instrument::Band,
-                            _ => Instrument::None,
+                        match section.replace(d.name(), "").as_str() {
+                            "single" => Some(Instrument::Guitar),
+                            "doublebass" => Some(Instrument::Bass),
+                            "doublerhythm" => Some(Instrument::Rhythm),
+                            "doubleguitar" => Some(Instrument::GuitarCoop),
+                            "ghlguitar" => Some(Instrument::GHLGuitar),
+                            "ghlbass" => Some(Instrument::GHLBass),
+                            "drums" => Some(Instrument::Drums),
+                            "keyboard" => Some(Instrument::Keys),
+                            "band" => Some(Instrument::Band),
+                            _ => None,
                         }
                     };
                     break;
@@ -239,11 +170,11 @@ fn read_chart(song: &mut SongEntry, buf: &[u8], full: bool) {
 
         // apply data when done with section
         if line == "}" {
-            if inst != Instrument::None && diff >= 0 && notes_flag {
-                apply_charts(song, inst, drums_flag, diff);
+            if let (Some(i), Some(d), true) = (inst, diff, notes_flag) {
+                apply_charts(song, i, drums_flag, d);
             }
-            inst = Instrument::None;
-            diff = -1i64;
+            inst = None;
+            diff = None;
             notes_flag = false;
             drums_flag = false;
         }
@@ -269,10 +200,8 @@ fn read_chart(song: &mut SongEntry, buf: &[u8], full: bool) {
                     "genre" => song.metadata[3] = val,
                     "album" => song.metadata[2] = val,
                     "year" => song.metadata[4] = val.replace(", ", ""),
-                    "name" => {
-                        if val != "TEMPO TRACK" && val != "" && val != "midi_export" {
-                            song.metadata[0] = val;
-                        }
+                    "name" if val != "TEMPO TRACK" && !val.is_empty() && val != "midi_export" => {
+                        song.metadata[0] = val;
                     }
                     _ => {}
                 }
@@ -289,9 +218,9 @@ fn read_chart(song: &mut SongEntry, buf: &[u8], full: bool) {
         }
 
         // difficulty and instrument parsing
-        if val.starts_with("N") {
+        if val.starts_with('N') {
             notes_flag = true;
-            if inst == Instrument::Drums
+            if inst == Some(Instrument::Drums)
                 && !drums_flag
                 && (val.starts_with("N 5")
                     || val.starts_with("N 32")
@@ -303,176 +232,632 @@ fn read_chart(song: &mut SongEntry, buf: &[u8], full: bool) {
             }
         }
     }
+
+    if sections == 0 {
+        warnings.push(String::from("notes.chart has no sections"));
+    }
+}
+
+// path relative to the root with / separators, for matching ignore patterns
+fn relative_path(p: &Path, root: &Path) -> String {
+    p.strip_prefix(root)
+        .unwrap_or(p)
+        .to_string_lossy()
+        .replace(MAIN_SEPARATOR, "/")
 }
 
-pub fn scan_folder(p: &Path, cloud_format: bool) -> Vec<SongEntry> {
+fn is_ignored(ignore: &IgnoreSet, p: &Path, root: &Path) -> bool {
+    let name = p.file_name().unwrap_or_default().to_string_lossy();
+    ignore.matches(&name, &relative_path(p, root))
+}
+
+pub fn scan_folder(p: &Path, cloud_format: bool) -> (Vec<SongEntry>, ScanReport) {
+    scan(&ScanConfig {
+        roots: vec![p.to_path_buf()],
+        cloud_format,
+        ..Default::default()
+    })
+}
+
+// scans every root of the config, duplicates are checked across all roots
+pub fn scan(config: &ScanConfig) -> (Vec<SongEntry>, ScanReport) {
+    let (songs, report) = scan_sources(config);
+    (songs.into_iter().map(|s| s.song).collect(), report)
+}
+
+// a song and the folder or archive it was read from
+#[derive(Debug, Clone)]
+pub struct ScannedSong {
+    pub source: PathBuf,
+    pub song: SongEntry,
+}
+
+// like scan, but keeps where every song came from
+pub fn scan_sources(config: &ScanConfig) -> (Vec<ScannedSong>, ScanReport) {
     let mut songs = vec![];
-    let mut checksums = vec![];
-
-    for entry in WalkDir::new(p) {
-        let entry = entry.unwrap();
-
-        if entry.path().extension().unwrap_or(&OsStr::new("")) == OsStr::new("sng") {
-            // todo
-        } else if entry.file_type().is_dir() {
-            let mut mid_flag = false;
-            let mut chart_flag = false;
-            let mut ini_flag = false;
-            let mut video_flag = false;
-            let mut chart_name = String::new();
-            let mut files = vec![];
-
-            // scan current folder
-            for file in fs::read_dir(entry.path()).unwrap() {
-                let file = file.unwrap();
-                let raw_name = file.file_name().to_string_lossy().to_string();
-                let name = file
-                    .path()
-                    .file_stem()
-                    .unwrap_or(&OsStr::new(""))
-                    .to_string_lossy()
-                    .to_lowercase();
-                let extension = file
-                    .path()
-                    .extension()
-                    .unwrap_or(&OsStr::new(""))
-                    .to_string_lossy()
-                    .to_lowercase();
-                if name == "notes" && (extension == "mid" || extension == "chart") {
-                    chart_name = raw_name.clone();
-                    if extension == "mid" {
-                        mid_flag = true;
-                    } else if extension == "chart" {
-                        chart_flag = true;
-                    }
-                } else {
-                    if files.len() < 256 {
-                        files.push(raw_name.clone());
-                    }
-                    if name == "song" && extension == "ini" {
-                        ini_flag = true;
-                    } else if name == "video" && VIDEO_EXTS.contains(&&extension[..]) {
-                        video_flag = true;
-                    }
+    let report = scan_each(config, |s| songs.push(s));
+    (songs, report)
+}
+
+// hands every song to on_song as soon as it is read instead of collecting them
+pub fn scan_each(config: &ScanConfig, mut on_song: impl FnMut(ScannedSong)) -> ScanReport {
+    let starts: Vec<(&Path, &Path)> = config.roots.iter().map(|r| (r.as_path(), r.as_path())).collect();
+    run(config, &starts, &[], &mut on_song)
+}
+
+/*
+   scans only the given folders or archives inside a root
+   known songs count for duplicate detection, so the folders
+   should be removed from them first
+*/
+pub fn rescan(config: &ScanConfig, root: &Path, paths: &[PathBuf], known: &[ScannedSong]) -> (Vec<ScannedSong>, ScanReport) {
+    let starts: Vec<(&Path, &Path)> = paths.iter().map(|p| (root, p.as_path())).collect();
+    let mut songs = vec![];
+    let report = run(config, &starts, known, &mut |s| songs.push(s));
+    (songs, report)
+}
+
+fn run(
+    config: &ScanConfig,
+    starts: &[(&Path, &Path)],
+    known: &[ScannedSong],
+    on_song: &mut dyn FnMut(ScannedSong),
+) -> ScanReport {
+    let timer = Instant::now();
+    let mut report = ScanReport::default();
+    let mut checksums: HashMap<[u8; 16], String> = known
+        .iter()
+        .map(|s| (s.song.checksum, s.source.to_string_lossy().to_string()))
+        .collect();
+    // ScanConfig::load rejects broken patterns, a config built in code is checked here
+    let ignore = match config.ignore_set() {
+        Ok(ignore) => ignore,
+        Err(e) => {
+            report.error(starts.first().map_or(Path::new(""), |s| s.0), e);
+            return report;
+        }
+    };
+
+    for (root, start) in starts {
+        let mut scan = Scan {
+            root,
+            config,
+            ignore: &ignore,
+            on_song: &mut *on_song,
+            checksums: &mut checksums,
+            report: &mut report,
+        };
+        if scan.failed() {
+            break;
+        }
+        if config.extract_archives {
+            scan.extract_all(start);
+        }
+        scan.walk(start);
+    }
+
+    report.duration_secs = timer.elapsed().as_secs_f64();
+    log::debug!("{} songs found", report.songs);
+    report
+}
+
+// the files of a song folder, on disk or inside an archive
+#[derive(Default)]
+struct Listing {
+    mid_flag: bool,
+    chart_flag: bool,
+    ini_name: Option<String>,
+    video_flag: bool,
+    art_name: Option<String>,
+    mogg_name: Option<String>,
+    chart_name: String,
+    files: Vec<String>,
+    // why the song can not be served as plain files, set by the caller
+    encrypted: Option<String>,
+}
+
+impl Listing {
+    fn add(&mut self, raw_name: &str, config: &ScanConfig) {
+        let p = Path::new(raw_name);
+        let name = p.file_stem().unwrap_or_default().to_string_lossy().to_lowercase();
+        let extension = p.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        if name == "notes" && (extension == "mid" || extension == "chart") {
+            self.chart_name = String::from(raw_name);
+            if extension == "mid" {
+                self.mid_flag = true;
+            } else if extension == "chart" {
+                self.chart_flag = true;
+            }
+        } else {
+            if self.files.len() < 256 {
+                self.files.push(String::from(raw_name));
+            }
+            if name == "song" && extension == "ini" {
+                self.ini_name = Some(String::from(raw_name));
+            } else if name == "video" && config.video_exts.iter().any(|e| e.eq_ignore_ascii_case(&extension)) {
+                self.video_flag = true;
+            } else if ART_NAMES.iter().any(|a| raw_name.eq_ignore_ascii_case(a)) {
+                self.art_name.get_or_insert_with(|| String::from(raw_name));
+            } else if extension == "mogg" {
+                self.mogg_name = Some(String::from(raw_name));
+            }
+        }
+    }
+
+    // only folders with notes are songs
+    fn is_song(&self) -> bool {
+        self.mid_flag || self.chart_flag
+    }
+}
+
+struct Scan<'a> {
+    root: &'a Path,
+    config: &'a ScanConfig,
+    ignore: &'a IgnoreSet,
+    on_song: &'a mut dyn FnMut(ScannedSong),
+    checksums: &'a mut HashMap<[u8; 16], String>,
+    report: &'a mut ScanReport,
+}
+
+impl Scan<'_> {
+    fn failed(&self) -> bool {
+        self.config.strict && !self.report.errors.is_empty()
+    }
+
+    fn walk(&mut self, start: &Path) {
+        let p = self.root;
+        let mut walker = WalkDir::new(start).into_iter();
+
+        while let Some(entry) = walker.next() {
+            if self.failed() {
+                return;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.report.error(e.path().unwrap_or(p), e.to_string());
+                    continue;
+                }
+            };
+            if entry.depth() > 0 && is_ignored(self.ignore, entry.path(), p) {
+                if entry.file_type().is_dir() {
+                    self.report.skip(entry.path(), SkipReason::Ignored);
+                    walker.skip_current_dir();
                 }
+                continue;
             }
 
-            // idk what this does but it works :tm:
-            if !(!mid_flag && !chart_flag) || ini_flag {
-                let s_path = entry.path();
-                let mut song = SongEntry::default();
-
-                if cloud_format {
-                    song.folder_path = format!(
-                        "/{}",
-                        s_path
-                            .strip_prefix(p)
-                            .unwrap()
-                            .to_string_lossy()
-                            .replace(MAIN_SEPARATOR, "/")
-                    );
-                } else {
-                    song.folder_path = s_path.to_string_lossy().to_lowercase().to_string();
+            let extension = entry.path().extension().unwrap_or_default().to_ascii_lowercase();
+            if extension == OsStr::new("sng") {
+                if entry.file_type().is_file() {
+                    self.scan_package(entry.path());
+                }
+            } else if extension == OsStr::new("zip") && entry.file_type().is_file() {
+                // extracted archives are scanned as folders
+                if !self.config.extract_archives {
+                    self.scan_archive(entry.path());
                 }
+            } else if entry.file_type().is_dir() {
+                self.scan_dir(entry.path());
+            }
+        }
+    }
 
-                // skip if song.ini is invalid
-                //if !read_ini(&mut song, &s_path.join("song.ini")) {
-                if !read_ini(&mut song, &s_path.join("song.ini")) {
+    fn scan_dir(&mut self, s_path: &Path) {
+        self.report.folders += 1;
+        let dir = match fs::read_dir(s_path) {
+            Ok(dir) => dir,
+            Err(e) => {
+                self.report.error(s_path, e.to_string());
+                return;
+            }
+        };
+
+        // scan current folder
+        let mut listing = Listing::default();
+        for file in dir {
+            let file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    self.report.error(s_path, e.to_string());
                     continue;
                 }
+            };
+            if is_ignored(self.ignore, &file.path(), self.root) {
+                continue;
+            }
+            listing.add(&file.file_name().to_string_lossy(), self.config);
+        }
 
-                // read all of the note data and metadata
-                let notes_data = {
-                    let mut f = File::open(s_path.join(&chart_name)).unwrap();
-                    let mut d = vec![];
-                    f.read_to_end(&mut d).unwrap();
-                    d
-                };
-
-                // calcute md5 checksum for the data
-                let check = md5::compute(&notes_data);
-                // check for duplicates
-                if checksums.contains(&check.0) {
-                    println!("duplicate {:?}", s_path);
-                    continue;
+        if listing.is_song() {
+            // the folder path would not point at the folder anymore
+            if s_path.to_str().is_none() {
+                self.report.error(s_path, String::from("path is not valid UTF-8"));
+                return;
+            }
+            if let Some(mogg) = &listing.mogg_name {
+                match File::open(s_path.join(mogg)).and_then(mogg_is_encrypted) {
+                    Ok(true) => listing.encrypted = Some(format!("encrypted {}", mogg)),
+                    Ok(false) => {}
+                    Err(e) => self.report.warn(s_path, format!("{}: {}", mogg, e)),
                 }
-                song.checksum = check.0;
-                checksums.push(check.0);
-
-                // reuse the data to read all needed metadata
-                if mid_flag {
-                    read_midi(&mut song, &notes_data);
-                } else if chart_flag {
-                    read_chart(&mut song, &notes_data, false);
+            }
+            let folder_path = match self.folder_path(s_path) {
+                Ok(f) => f,
+                Err(e) => {
+                    self.report.error(s_path, e);
+                    return;
                 }
+            };
+            self.add_song(s_path, s_path, folder_path, None, &listing, |name| {
+                fs::read(s_path.join(name)).map_err(|e| format!("{}: {}", name, e))
+            });
+        } else if listing.ini_name.is_some() {
+            self.report.skip(s_path, SkipReason::NoNotes);
+        }
+    }
 
-                // add some stuffs
-                song.video_background = video_flag;
-                song.chart_name = chart_name;
-                song.date_added = 0; //DateTime.Now.Date;
-                if cloud_format {
-                    for ff in files {
-                        song.chart_name += format!("\n{}", ff).as_str();
-                    }
+    /*
+       every folder inside the archive with a notes file is a song
+       the folder path is the archive path followed by the folder inside it
+    */
+    fn scan_archive(&mut self, z_path: &Path) {
+        let mut archive = match File::open(z_path).map_err(|e| e.to_string()).and_then(|f| {
+            zip::ZipArchive::new(BufReader::new(f)).map_err(|e| e.to_string())
+        }) {
+            Ok(a) => a,
+            Err(e) => {
+                self.report.error(z_path, e);
+                return;
+            }
+        };
+        if z_path.to_str().is_none() {
+            self.report.error(z_path, String::from("path is not valid UTF-8"));
+            return;
+        }
+
+        // group the files by the folder they are in
+        let mut folders: Vec<(String, Listing)> = vec![];
+        for name in archive.file_names() {
+            let ignored = Path::new(name)
+                .ancestors()
+                .any(|a| !a.as_os_str().is_empty() && is_ignored(self.ignore, a, Path::new("")));
+            if name.ends_with('/') || ignored {
+                continue;
+            }
+            let (folder, file) = name.rsplit_once('/').unwrap_or(("", name));
+            let i = match folders.iter().position(|(f, _)| f == folder) {
+                Some(i) => i,
+                None => {
+                    folders.push((String::from(folder), Listing::default()));
+                    folders.len() - 1
                 }
+            };
+            folders[i].1.add(file, self.config);
+        }
 
-                // fix empty metadata
-                for m in 0..song.metadata.len() {
-                    if song.metadata[m].trim() == "" {
-                        song.metadata[m] = String::from(METADATA_DEFAULTS[m]);
-                    }
+        let z_folder_path = match self.folder_path(z_path) {
+            Ok(f) => f,
+            Err(e) => {
+                self.report.error(z_path, e);
+                return;
+            }
+        };
+        for (folder, listing) in folders.iter_mut().filter(|(_, l)| l.is_song()) {
+            let mut folder_path = z_folder_path.clone();
+            if !folder.is_empty() {
+                let sep = if self.config.cloud_format { '/' } else { MAIN_SEPARATOR };
+                folder_path = format!("{}{}{}", folder_path, sep, folder.replace('/', &sep.to_string()));
+                if !self.config.cloud_format {
+                    folder_path = folder_path.to_lowercase();
+                }
+            }
+            let source = ArchiveSource {
+                path: z_path.to_string_lossy().to_string(),
+                folder: folder.clone(),
+            };
+            let s_path = if folder.is_empty() { z_path.to_path_buf() } else { z_path.join(&*folder) };
+            if let Some(mogg) = &listing.mogg_name {
+                let inner = if folder.is_empty() { mogg.clone() } else { format!("{}/{}", folder, mogg) };
+                let encrypted = archive.by_name(&inner).map_err(io::Error::from).and_then(mogg_is_encrypted);
+                match encrypted {
+                    Ok(true) => listing.encrypted = Some(format!("encrypted {}", mogg)),
+                    Ok(false) => {}
+                    Err(e) => self.report.warn(&s_path, format!("{}: {}", mogg, e)),
                 }
+            }
+            self.add_song(z_path, &s_path, folder_path, Some(source), listing, |name| {
+                let inner = if folder.is_empty() { name.to_string() } else { format!("{}/{}", folder, name) };
+                let mut f = archive.by_name(&inner).map_err(|e| format!("{}: {}", inner, e))?;
+                let mut d = vec![];
+                f.read_to_end(&mut d).map_err(|e| format!("{}: {}", inner, e))?;
+                Ok(d)
+            });
+        }
+    }
 
-                // set last metadata element and top_level_playlist
-                if song.top_level_playlist == String::from("") {
-                    // populate element
-                    let mut tempdata = song.folder_path.clone();
-                    if tempdata.bytes().nth(tempdata.len() - 1).unwrap() == MAIN_SEPARATOR as u8 {
-                        tempdata.remove(tempdata.len() - 1);
-                    }
-                    tempdata =
-                        String::from(tempdata.get(p.to_string_lossy().len()..).unwrap_or(""));
-                    let mut num = -1;
-                    if tempdata.len() > 0 {
-                        tempdata.remove(0);
-                        num = tempdata.rfind(MAIN_SEPARATOR).unwrap_or(0) as i32;
-                    }
-                    song.metadata[6] = {
-                        if num == -1 {
-                            String::from("")
-                        } else {
-                            String::from(tempdata.get(..num as usize).unwrap_or(""))
-                        }
-                    };
-                    // create top_level_playlist
-                    if song.metadata[6] != String::from("") {
-                        let temppos = song.metadata[6].find(MAIN_SEPARATOR);
-                        song.top_level_playlist = {
-                            if temppos.is_none() {
-                                song.metadata[6].clone()
-                            } else {
-                                String::from(song.metadata[6].get(..temppos.unwrap()).unwrap())
-                            }
-                        }
-                        .to_lowercase();
+    /*
+       a .sng package is a single song, its metadata takes the place of song.ini
+       the game reads it directly, clients can not download its files one by one
+    */
+    fn scan_package(&mut self, p: &Path) {
+        let mut package = match File::open(p).and_then(|f| SngPackage::new(BufReader::new(f))) {
+            Ok(package) => package,
+            Err(e) => {
+                self.report.error(p, e.to_string());
+                return;
+            }
+        };
+        if p.to_str().is_none() {
+            self.report.error(p, String::from("path is not valid UTF-8"));
+            return;
+        }
+
+        let mut listing = Listing::default();
+        for file in &package.files {
+            listing.add(&file.name, self.config);
+        }
+        if listing.ini_name.is_none() && !package.metadata.is_empty() {
+            listing.add("song.ini", self.config);
+        }
+        if !listing.is_song() {
+            if listing.ini_name.is_some() {
+                self.report.skip(p, SkipReason::NoNotes);
+            }
+            return;
+        }
+        listing.encrypted = Some(String::from("sng package"));
+
+        let ini = package.song_ini();
+        let folder_path = match self.folder_path(p) {
+            Ok(f) => f,
+            Err(e) => {
+                self.report.error(p, e);
+                return;
+            }
+        };
+        self.add_song(p, p, folder_path, None, &listing, |name| match package.read(name) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound && name == "song.ini" => Ok(ini.clone().into_bytes()),
+            result => result.map_err(|e| format!("{}: {}", name, e)),
+        });
+    }
+
+    // cloud paths are relative to the root, which fails for folders reached through a link
+    fn folder_path(&self, s_path: &Path) -> Result<String, String> {
+        if self.config.cloud_format {
+            let rel = s_path
+                .strip_prefix(self.root)
+                .map_err(|_| format!("not inside the song folder {}", self.root.display()))?;
+            Ok(format!("/{}", rel.to_string_lossy().replace(MAIN_SEPARATOR, "/")))
+        } else {
+            Ok(s_path.to_string_lossy().to_lowercase().to_string())
+        }
+    }
+
+    /*
+       reads a song from its files, read returns the content of a file in the folder
+       source is the folder or archive on disk, s_path the folder of the song
+    */
+    fn add_song(
+        &mut self,
+        source: &Path,
+        s_path: &Path,
+        folder_path: String,
+        archive: Option<ArchiveSource>,
+        listing: &Listing,
+        mut read: impl FnMut(&str) -> Result<Vec<u8>, String>,
+    ) {
+        let p = self.root;
+        let report = &mut *self.report;
+        let mut song = SongEntry {
+            folder_path,
+            archive,
+            is_enc: listing.encrypted.is_some(),
+            ..Default::default()
+        };
+
+        // fall back to the chart metadata if song.ini is missing or invalid
+        let mut warnings = vec![];
+        let fallback = match &listing.ini_name {
+            Some(ini_name) => match read(ini_name) {
+                Ok(buf) if read_ini(&mut song, &buf) => false,
+                Ok(_) => {
+                    warnings.push(format!("{} has no [song] section", ini_name));
+                    true
+                }
+                Err(e) => {
+                    report.error(s_path, e);
+                    return;
+                }
+            },
+            None => true,
+        };
+
+        // read all of the note data and metadata
+        let notes_data = match read(&listing.chart_name) {
+            Ok(d) => d,
+            Err(e) => {
+                report.error(s_path, e);
+                return;
+            }
+        };
+
+        // calcute md5 checksum for the data
+        let check = md5::compute(&notes_data);
+        // check for duplicates
+        if let Some(original) = self.checksums.get(&check.0) {
+            report.duplicate(s_path, original, util::checksum_hex(&check.0));
+            return;
+        }
+        song.checksum = check.0;
+
+        // reuse the data to read all needed metadata
+        if listing.mid_flag {
+            if let Err(e) = read_midi(&mut song, &notes_data, fallback, &mut warnings) {
+                report.error(s_path, e);
+                return;
+            }
+        } else if listing.chart_flag {
+            read_chart(&mut song, &notes_data, fallback, &mut warnings);
+        }
+        self.checksums.insert(check.0, s_path.to_string_lossy().to_string());
+        if fallback {
+            report.fallback_metadata.push(s_path.to_string_lossy().to_string());
+        }
+
+        if let Some(dir) = &self.config.output.art {
+            // the cover named in song.ini comes first, if it is inside the song folder
+            let mut cover = song.ini.as_ref().and_then(|i| i.cover.clone());
+            if let Some(name) = cover.take_if(|n| !art::is_local_name(n)) {
+                warnings.push(format!("album art {} is outside the song folder", name));
+            }
+            let mut error = None;
+            for name in cover.iter().chain(&listing.art_name) {
+                let made = read(name).and_then(|data| {
+                    art::make_art(name, &data, &self.config.art, dir).map_err(|e| format!("{}: {}", name, e))
+                });
+                match made {
+                    Ok(a) => {
+                        song.art = Some(a);
+                        break;
                     }
-                    song.sub_playlist = String::from("");
+                    Err(e) => error = Some(format!("album art {}", e)),
+                }
+            }
+            if let (None, Some(e)) = (&song.art, error) {
+                warnings.push(e);
+            }
+        }
+
+        // add some stuffs
+        song.video_background = listing.video_flag;
+        song.chart_name = listing.chart_name.clone();
+        song.date_added = 0; //DateTime.Now.Date;
+        if self.config.cloud_format {
+            for ff in &listing.files {
+                song.chart_name += format!("\n{}", ff).as_str();
+            }
+        }
+        if song.chart_name.len() > 250 {
+            warnings.push(format!("chart_name is {} bytes long", song.chart_name.len()));
+        }
+        for w in warnings {
+            report.warn(s_path, w);
+        }
+
+        // fix empty metadata
+        for (m, default) in song.metadata.iter_mut().zip(&self.config.metadata_defaults) {
+            if m.trim().is_empty() {
+                *m = default.clone();
+            }
+        }
+
+        // set last metadata element and top_level_playlist
+        if song.top_level_playlist.is_empty() {
+            // populate element
+            let mut tempdata = song.folder_path.clone();
+            if tempdata.ends_with(MAIN_SEPARATOR) {
+                tempdata.remove(tempdata.len() - 1);
+            }
+            tempdata = String::from(tempdata.get(p.to_string_lossy().len()..).unwrap_or(""));
+            let mut num = -1;
+            if !tempdata.is_empty() {
+                tempdata.remove(0);
+                num = tempdata.rfind(MAIN_SEPARATOR).unwrap_or(0) as i32;
+            }
+            song.metadata[6] = {
+                if num == -1 {
+                    String::from("")
                 } else {
-                    song.metadata[6] = format!("{}{}", song.top_level_playlist, {
-                        if song.sub_playlist != String::from("") {
-                            format!("{}{}", MAIN_SEPARATOR, song.sub_playlist)
-                        } else {
-                            String::from("")
-                        }
-                    })
+                    String::from(tempdata.get(..num as usize).unwrap_or(""))
                 }
+            };
+            // create top_level_playlist
+            if !song.metadata[6].is_empty() {
+                let temppos = song.metadata[6].find(MAIN_SEPARATOR);
+                song.top_level_playlist = {
+                    match temppos {
+                        None => song.metadata[6].clone(),
+                        Some(pos) => String::from(song.metadata[6].get(..pos).unwrap()),
+                    }
+                }
+                .to_lowercase();
+            }
+            song.sub_playlist = String::from("");
+        } else {
+            song.metadata[6] = playlist::playlist_metadata(&song.top_level_playlist, &song.sub_playlist);
+        }
+
+        if let Some(reason) = &listing.encrypted {
+            self.report.unservable(s_path, reason.clone());
+        }
+        self.report.songs += 1;
+        (self.on_song)(ScannedSong {
+            source: source.to_path_buf(),
+            song,
+        });
+    }
 
-                songs.push(song);
-                //println!("{:?}", songs.len());
+    // unpacks every archive below start next to itself, once
+    fn extract_all(&mut self, start: &Path) {
+        let zips: Vec<PathBuf> = WalkDir::new(start)
+            .into_iter()
+            .filter_entry(|e| e.depth() == 0 || !is_ignored(self.ignore, e.path(), self.root))
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.into_path())
+            .filter(|p| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("zip")))
+            .collect();
+
+        for z in zips {
+            let out = z.with_extension("");
+            if out.exists() {
+                continue;
+            }
+            match extract_archive(&z, &out) {
+                Ok(()) => log::info!("extracted {}", z.display()),
+                Err(e) => self.report.error(&z, e),
             }
         }
     }
+}
 
-    println!("{:?}", songs.len());
-    songs
+// a mogg starts with its version, 10 is plain ogg vorbis, later versions are encrypted
+fn mogg_is_encrypted<R: Read>(mut f: R) -> io::Result<bool> {
+    Ok(f.read_u8()? != 0x0a)
+}
+
+// unpacks a zip archive into a folder, entries pointing outside of it are refused
+pub fn extract_archive(z_path: &Path, out: &Path) -> Result<(), String> {
+    let f = File::open(z_path).map_err(|e| e.to_string())?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(f)).map_err(|e| e.to_string())?;
+    archive.extract(out).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::temp_path;
+
+    #[test]
+    fn folders_outside_the_root_are_song_errors() {
+        let dir = temp_path("outside");
+        let song = dir.join("song");
+        fs::create_dir_all(&song).unwrap();
+        fs::write(song.join("song.ini"), "[song]\nname = A\n").unwrap();
+        fs::write(song.join("notes.chart"), "[Song]\n{\n}\n").unwrap();
+
+        let config = ScanConfig {
+            cloud_format: true,
+            ..Default::default()
+        };
+        let (songs, report) = rescan(&config, &dir.join("root"), std::slice::from_ref(&song), &[]);
+        let (inside, inside_report) = rescan(&config, &dir, &[song], &[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(songs.is_empty());
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(inside[0].song.folder_path, "/song");
+        assert!(inside_report.errors.is_empty());
+    }
 }
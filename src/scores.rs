@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use crate::instrument::{Difficulty, Instrument};
+use crate::songentry::SongEntry;
+use crate::util::{self, invalid, PREALLOCATE};
+
+/*
+   player scores as clone hero keeps them in scoredata.bin
+   version i32, song count i32, then per song:
+   checksum [16], score count u8, play count u24,
+   and per score: instrument i16, difficulty u8, percent numerator i16,
+   percent denominator i16, stars u8, unknown i32, score i32
+*/
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ScoreData {
+    pub version: i32,
+    pub songs: Vec<SongScores>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SongScores {
+    #[serde(deserialize_with = "util::deserialize_checksum")]
+    pub checksum: [u8; 16], // same as SongEntry::checksum
+    pub play_count: u32,    // only 24 bits are stored
+    pub scores: Vec<Score>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Score {
+    pub instrument: i16,
+    pub difficulty: u8,
+    pub percent_hit: i16,   // notes hit
+    pub percent_total: i16, // notes in the chart
+    pub stars: u8,
+    pub unknown: i32,       // kept as read
+    pub score: i32,
+}
+
+impl Score {
+    pub fn instrument(&self) -> Option<Instrument> {
+        Instrument::ALL.into_iter().find(|i| *i as i16 == self.instrument)
+    }
+
+    pub fn difficulty(&self) -> Option<Difficulty> {
+        Difficulty::ALL.into_iter().find(|d| *d as u8 == self.difficulty)
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.percent_total <= 0 {
+            return 0.0;
+        }
+        self.percent_hit as f64 * 100.0 / self.percent_total as f64
+    }
+
+    // e.g. "guitar expert", raw numbers for anything unknown
+    pub fn describe(&self) -> String {
+        let inst = self.instrument().map_or(self.instrument.to_string(), |i| i.name().to_string());
+        let diff = self.difficulty().map_or(self.difficulty.to_string(), |d| d.name().to_string());
+        format!("{} {}", inst, diff)
+    }
+}
+
+fn read_score<R: Read>(f: &mut R) -> io::Result<Score> {
+    Ok(Score {
+        instrument: f.read_i16::<LittleEndian>()?,
+        difficulty: f.read_u8()?,
+        percent_hit: f.read_i16::<LittleEndian>()?,
+        percent_total: f.read_i16::<LittleEndian>()?,
+        stars: f.read_u8()?,
+        unknown: f.read_i32::<LittleEndian>()?,
+        score: f.read_i32::<LittleEndian>()?,
+    })
+}
+
+pub fn read_scores<R: Read>(f: &mut R) -> io::Result<ScoreData> {
+    let version = f.read_i32::<LittleEndian>()?;
+    log::debug!("score version {}", version);
+    let count = f.read_i32::<LittleEndian>()?;
+    if count < 0 {
+        return Err(invalid(format!("negative song count {}", count)));
+    }
+
+    let mut songs = Vec::with_capacity((count as usize).min(PREALLOCATE));
+    for _ in 0..count {
+        let mut checksum = [0; 16];
+        f.read_exact(&mut checksum)?;
+        let n = f.read_u8()?;
+        let play_count = f.read_u24::<LittleEndian>()?;
+        let scores = (0..n).map(|_| read_score(f)).collect::<io::Result<Vec<_>>>()?;
+        log::trace!("{} {} scores", util::checksum_hex(&checksum), scores.len());
+        songs.push(SongScores {
+            checksum,
+            play_count,
+            scores,
+        });
+    }
+    Ok(ScoreData { version, songs })
+}
+
+pub fn write_scores<W: Write>(data: &ScoreData, f: &mut W) -> io::Result<()> {
+    f.write_i32::<LittleEndian>(data.version)?;
+    f.write_i32::<LittleEndian>(data.songs.len() as i32)?;
+    for song in &data.songs {
+        if song.scores.len() > u8::MAX as usize {
+            return Err(invalid(format!("{} has more than 255 scores", util::checksum_hex(&song.checksum))));
+        }
+        f.write_all(&song.checksum)?;
+        f.write_u8(song.scores.len() as u8)?;
+        f.write_u24::<LittleEndian>(song.play_count.min(0xff_ffff))?;
+        for s in &song.scores {
+            f.write_i16::<LittleEndian>(s.instrument)?;
+            f.write_u8(s.difficulty)?;
+            f.write_i16::<LittleEndian>(s.percent_hit)?;
+            f.write_i16::<LittleEndian>(s.percent_total)?;
+            f.write_u8(s.stars)?;
+            f.write_i32::<LittleEndian>(s.unknown)?;
+            f.write_i32::<LittleEndian>(s.score)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+   merges score files, e.g. backups of the same player
+   songs are joined by checksum, the highest play count is kept and for
+   every instrument and difficulty the highest score
+   the version of the first file is used
+*/
+pub fn merge_scores(all: Vec<ScoreData>) -> ScoreData {
+    let mut out = ScoreData {
+        version: all.first().map_or(0, |d| d.version),
+        songs: vec![],
+    };
+    let mut positions: HashMap<[u8; 16], usize> = HashMap::new();
+
+    for data in all {
+        for song in data.songs {
+            let Some(&i) = positions.get(&song.checksum) else {
+                positions.insert(song.checksum, out.songs.len());
+                out.songs.push(song);
+                continue;
+            };
+            let kept = &mut out.songs[i];
+            kept.play_count = kept.play_count.max(song.play_count);
+            for score in song.scores {
+                let same = kept
+                    .scores
+                    .iter_mut()
+                    .find(|s| s.instrument == score.instrument && s.difficulty == score.difficulty);
+                match same {
+                    Some(s) if s.score < score.score => *s = score,
+                    Some(_) => {}
+                    None => kept.scores.push(score),
+                }
+            }
+        }
+    }
+    out
+}
+
+// scores next to the song they belong to, song is None if it is not in the cache
+#[derive(Serialize, Debug)]
+pub struct ScoredSong<'a> {
+    pub checksum: String,
+    pub song: Option<&'a SongEntry>,
+    pub play_count: u32,
+    pub scores: &'a [Score],
+}
+
+// joins scores to songs by checksum, in the order of the score file
+pub fn join<'a>(data: &'a ScoreData, songs: &'a [SongEntry]) -> Vec<ScoredSong<'a>> {
+    let by_checksum: HashMap<[u8; 16], &SongEntry> = songs.iter().map(|s| (s.checksum, s)).collect();
+    data.songs
+        .iter()
+        .map(|s| ScoredSong {
+            checksum: util::checksum_hex(&s.checksum),
+            song: by_checksum.get(&s.checksum).copied(),
+            play_count: s.play_count,
+            scores: &s.scores,
+        })
+        .collect()
+}
+
+// human readable listing, one line per song and one per score
+pub fn summary(scored: &[ScoredSong]) -> String {
+    let mut out = String::new();
+    for s in scored {
+        let title = match s.song {
+            Some(song) => format!("{} - {}", song.metadata[1], song.metadata[0]),
+            None => s.checksum.clone(),
+        };
+        out += &format!("{} (played {} times)\n", title, s.play_count);
+        for score in s.scores {
+            out += &format!(
+                "    {}: {}, {} stars, {:.2}%\n",
+                score.describe(),
+                score.score,
+                score.stars,
+                score.percent()
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn score(instrument: i16, difficulty: u8, score: i32) -> Score {
+        Score {
+            instrument,
+            difficulty,
+            percent_hit: 90,
+            percent_total: 100,
+            stars: 5,
+            unknown: -1,
+            score,
+        }
+    }
+
+    fn song(id: u8, play_count: u32, scores: Vec<Score>) -> SongScores {
+        SongScores {
+            checksum: [id; 16],
+            play_count,
+            scores,
+        }
+    }
+
+    fn scores() -> ScoreData {
+        ScoreData {
+            version: 20211121,
+            songs: vec![song(1, 3, vec![score(0, 3, 1000), score(4, 2, 500)]), song(2, 0x12_3456, vec![])],
+        }
+    }
+
+    #[test]
+    fn round_trips_scores() {
+        let mut out = vec![];
+        write_scores(&scores(), &mut out).unwrap();
+        assert_eq!(out.len(), 8 + 2 * 20 + 2 * 16);
+
+        let read = read_scores(&mut Cursor::new(&out)).unwrap();
+        assert_eq!(read.version, 20211121);
+        assert_eq!(read.songs[1].play_count, 0x12_3456);
+        assert_eq!(read.songs[0].scores, scores().songs[0].scores);
+
+        let mut again = vec![];
+        write_scores(&read, &mut again).unwrap();
+        assert_eq!(again, out);
+        assert!(read_scores(&mut Cursor::new(&out[..out.len() - 1])).is_err());
+    }
+
+    #[test]
+    fn play_count_keeps_24_bits() {
+        let data = ScoreData {
+            version: 1,
+            songs: vec![song(1, u32::MAX, vec![])],
+        };
+        let mut out = vec![];
+        write_scores(&data, &mut out).unwrap();
+        assert_eq!(read_scores(&mut Cursor::new(&out)).unwrap().songs[0].play_count, 0xff_ffff);
+    }
+
+    #[test]
+    fn merge_keeps_the_best() {
+        let other = ScoreData {
+            version: 1,
+            songs: vec![song(3, 1, vec![]), song(1, 2, vec![score(0, 3, 2000), score(4, 2, 100), score(1, 3, 50)])],
+        };
+        let merged = merge_scores(vec![scores(), other]);
+        assert_eq!(merged.version, 20211121);
+
+        let checksums: Vec<u8> = merged.songs.iter().map(|s| s.checksum[0]).collect();
+        assert_eq!(checksums, [1, 2, 3]);
+        assert_eq!(merged.songs[0].play_count, 3);
+        assert_eq!(merged.songs[0].scores, [score(0, 3, 2000), score(4, 2, 500), score(1, 3, 50)]);
+    }
+}
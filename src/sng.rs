@@ -0,0 +1,199 @@
+use std::io::{self, prelude::*, SeekFrom};
+use byteorder::{LittleEndian, ReadBytesExt};
+use crate::util::{self, invalid, PREALLOCATE};
+
+pub const MAGIC: &[u8; 6] = b"SNGPKG";
+
+/*
+   .sng song package, all files of a song in one file
+   "SNGPKG", version u32, xor mask [16],
+   metadata: length u64, count u64, then key and value as i32 length prefixed strings,
+   file index: length u64, count u64, then per file a u8 length prefixed name,
+   its length u64 and its offset u64 from the start of the package,
+   file data: length u64, then the contents of every file, masked
+*/
+pub struct SngPackage<R> {
+    f: R,
+    pub version: u32,
+    xor_mask: [u8; 16],
+    pub metadata: Vec<(String, String)>,
+    pub files: Vec<SngFile>,
+}
+
+pub struct SngFile {
+    pub name: String,
+    pub len: u64,
+    offset: u64,
+}
+
+// lengths are checked against the rest of the package before anything is read
+fn read_string<R: Read + Seek>(f: &mut R, len: u64, size: u64) -> io::Result<String> {
+    if len > size.saturating_sub(f.stream_position()?) {
+        return Err(invalid(format!("length {} is past the end of the package", len)));
+    }
+    let buf = util::read_bytes(f, len)?;
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+fn read_len<R: Read>(f: &mut R) -> io::Result<u64> {
+    let len = f.read_i32::<LittleEndian>()?;
+    u64::try_from(len).map_err(|_| invalid(format!("negative length {}", len)))
+}
+
+// true if the data starts like a package
+pub fn is_sng(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+impl<R: Read + Seek> SngPackage<R> {
+    // reads the metadata and the file index, contents are read on demand
+    pub fn new(mut f: R) -> io::Result<SngPackage<R>> {
+        let size = f.seek(SeekFrom::End(0))?;
+        f.rewind()?;
+        let mut magic = [0; 6];
+        f.read_exact(&mut magic)?;
+        if !is_sng(&magic) {
+            return Err(invalid(String::from("not a sng package")));
+        }
+        let version = f.read_u32::<LittleEndian>()?;
+        let mut xor_mask = [0; 16];
+        f.read_exact(&mut xor_mask)?;
+
+        let _ = f.read_u64::<LittleEndian>()?;
+        let count = f.read_u64::<LittleEndian>()?;
+        let mut metadata = Vec::with_capacity((count as usize).min(PREALLOCATE));
+        for _ in 0..count {
+            let len = read_len(&mut f)?;
+            let key = read_string(&mut f, len, size)?;
+            let len = read_len(&mut f)?;
+            metadata.push((key, read_string(&mut f, len, size)?));
+        }
+
+        let _ = f.read_u64::<LittleEndian>()?;
+        let count = f.read_u64::<LittleEndian>()?;
+        let mut files = Vec::with_capacity((count as usize).min(PREALLOCATE));
+        for _ in 0..count {
+            let len = f.read_u8()? as u64;
+            let file = SngFile {
+                name: read_string(&mut f, len, size)?,
+                len: f.read_u64::<LittleEndian>()?,
+                offset: f.read_u64::<LittleEndian>()?,
+            };
+            if file.offset.checked_add(file.len).is_none_or(|end| end > size) {
+                return Err(invalid(format!("{} is past the end of the package", file.name)));
+            }
+            files.push(file);
+        }
+
+        Ok(SngPackage {
+            f,
+            version,
+            xor_mask,
+            metadata,
+            files,
+        })
+    }
+
+    // unmasked contents of a file, names are compared case insensitive
+    pub fn read(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        let file = self
+            .files
+            .iter()
+            .find(|f| f.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the package", name)))?;
+
+        self.f.seek(SeekFrom::Start(file.offset))?;
+        let mut data = util::read_bytes(&mut self.f, file.len)?;
+        for (i, b) in data.iter_mut().enumerate() {
+            *b ^= self.xor_mask[i % 16] ^ (i & 0xff) as u8;
+        }
+        Ok(data)
+    }
+
+    // the metadata as song.ini text
+    pub fn song_ini(&self) -> String {
+        let mut out = String::from("[song]\n");
+        for (key, value) in &self.metadata {
+            out += &format!("{} = {}\n", key, value);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+
+    const MASK: [u8; 16] = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8, 9, 7, 9, 3];
+
+    fn package(metadata: &[(&str, &str)], files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut meta = vec![];
+        meta.write_u64::<LittleEndian>(metadata.len() as u64).unwrap();
+        for (key, value) in metadata {
+            for s in [key, value] {
+                meta.write_i32::<LittleEndian>(s.len() as i32).unwrap();
+                meta.extend_from_slice(s.as_bytes());
+            }
+        }
+
+        let index_len: usize = 8 + files.iter().map(|(name, _)| 1 + name.len() + 16).sum::<usize>();
+        let mut offset = (MAGIC.len() + 4 + 16 + 8 + meta.len() + 8 + index_len + 8) as u64;
+        let mut index = vec![];
+        index.write_u64::<LittleEndian>(files.len() as u64).unwrap();
+        let mut data = vec![];
+        for (name, contents) in files {
+            index.write_u8(name.len() as u8).unwrap();
+            index.extend_from_slice(name.as_bytes());
+            index.write_u64::<LittleEndian>(contents.len() as u64).unwrap();
+            index.write_u64::<LittleEndian>(offset).unwrap();
+            offset += contents.len() as u64;
+            data.extend(contents.iter().enumerate().map(|(i, b)| b ^ MASK[i % 16] ^ (i & 0xff) as u8));
+        }
+
+        let mut out = MAGIC.to_vec();
+        out.write_u32::<LittleEndian>(1).unwrap();
+        out.extend_from_slice(&MASK);
+        for section in [meta, index, data] {
+            out.write_u64::<LittleEndian>(section.len() as u64).unwrap();
+            out.extend(section);
+        }
+        out
+    }
+
+    #[test]
+    fn reads_unmasked_files() {
+        let long: Vec<u8> = (0..600).map(|i| (i * 7) as u8).collect();
+        let data = package(&[("name", "Song"), ("artist", "Band")], &[("notes.chart", b"[Song]\n{\n}\n"), ("song.opus", &long)]);
+        assert!(is_sng(&data));
+
+        let mut sng = SngPackage::new(Cursor::new(data)).unwrap();
+        assert_eq!(sng.version, 1);
+        assert_eq!(sng.files.len(), 2);
+        assert_eq!(sng.files[1].len, 600);
+        assert_eq!(sng.read("NOTES.CHART").unwrap(), b"[Song]\n{\n}\n");
+        assert_eq!(sng.read("song.opus").unwrap(), long);
+        assert_eq!(sng.read("missing.mid").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(sng.song_ini(), "[song]\nname = Song\nartist = Band\n");
+    }
+
+    #[test]
+    fn rejects_broken_packages() {
+        assert!(!is_sng(b"SNG"));
+        assert!(SngPackage::new(Cursor::new(b"NOTSNG0000".to_vec())).is_err());
+
+        let data = package(&[], &[("song.ini", b"[song]\n")]);
+        assert!(SngPackage::new(Cursor::new(data[..data.len() - 1].to_vec())).is_err());
+
+        // a length of i32::MAX in the metadata of a small package
+        let mut huge = package(&[("name", "Song")], &[]);
+        let key_len = MAGIC.len() + 4 + 16 + 8 + 8;
+        huge[key_len..key_len + 4].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert_eq!(SngPackage::new(Cursor::new(huge)).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let mut negative = package(&[("name", "Song")], &[]);
+        negative[key_len..key_len + 4].copy_from_slice(&(-1i32).to_le_bytes());
+        assert!(SngPackage::new(Cursor::new(negative)).is_err());
+    }
+}
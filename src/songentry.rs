@@ -1,21 +1,38 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use crate::art::ArtFormat;
+use crate::instrument::{Chart, Charts, Difficulty, Instrument, Intensities};
+use crate::songini::SongIni;
+use crate::util;
 
 const EMPTY_STRING: String = String::new();
 
-#[derive(Serialize, Debug)]
+// names of the elements in SongEntry::metadata
+pub const METADATA_NAMES: [&str; 7] = [
+    "name",
+    "artist",
+    "album",
+    "genre",
+    "year",
+    "charter",
+    "playlist",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct SongEntry {
 
     // normal format
     pub album_track: i16,           // short
     pub chart_name: String,         // string
-    pub charts: i64,                // GStruct6
+    pub charts: Charts,             // GStruct6
+    #[serde(deserialize_with = "util::deserialize_checksum")]
     pub checksum: [u8; 16],         // SongHash
     pub date_added: i64,            // DateTime
     pub folder_path: String,        // string
     pub force_five_lane: bool,      // bool
     pub force_pro_drums: bool,      // bool
     pub icon_name: String,          // string
-    pub intensities: [i8; 10],      // sbyte[]
+    pub intensities: Intensities,   // sbyte[]
     pub is_enc: bool,               // bool
     pub lyrics: bool,               // bool
     pub metadata: [String; 7],      // GClass47[]
@@ -27,6 +44,18 @@ pub struct SongEntry {
     pub top_level_playlist: String, // string
     pub video_background: bool,     // bool
 
+    // parsed song.ini, only available when scanning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ini: Option<SongIni>,
+
+    // zip archive the song was read from, only available when scanning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveSource>,
+
+    // scaled album art, only available when scanning with an art folder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub art: Option<AlbumArt>,
+
     // unused stuff from internal script
     //containers: String,           // dict<string, GClass9> PRIVATE
     //filtered: bool,               // bool
@@ -35,22 +64,22 @@ pub struct SongEntry {
     //is_type_cached: bool,         // bool PRIVATE
     //metadata_cache: String,       // string[] PRIVATE
     //metadata_loaded: bool,        // bool
-    //scores: String,               // GClass55
+    //scores: String,               // GClass55, kept in scoredata.bin, see scores.rs
     //song_enc: String,             // GClass9
 }
-impl SongEntry {
-    pub fn default() -> SongEntry {
+impl Default for SongEntry {
+    fn default() -> SongEntry {
         SongEntry {
             album_track: 16000,
             chart_name: EMPTY_STRING,
-            charts: 0,
+            charts: Charts::default(),
             checksum: [0; 16],
             date_added: 0,
             folder_path: EMPTY_STRING,
             force_five_lane: false,
             force_pro_drums: false,
             icon_name: EMPTY_STRING,
-            intensities: [-1; 10],
+            intensities: Intensities::default(),
             is_enc: false,
             lyrics: false,
             metadata: [EMPTY_STRING; 7],
@@ -61,6 +90,43 @@ impl SongEntry {
             sub_playlist: EMPTY_STRING,
             top_level_playlist: EMPTY_STRING,
             video_background: false,
+            ini: None,
+            archive: None,
+            art: None,
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveSource {
+    pub path: String,   // the archive on disk
+    pub folder: String, // folder of the song inside the archive, "" for the top
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlbumArt {
+    pub file: String, // the art in the song folder
+    pub width: u32,
+    pub height: u32,
+    pub images: Vec<ArtImage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArtImage {
+    pub path: String, // relative to the art folder
+    pub format: ArtFormat,
+    pub size: u32,    // the square the image was fit into
+    pub width: u32,
+    pub height: u32,
+}
+
+impl SongEntry {
+    pub fn has_chart(&self, inst: Instrument, diff: Difficulty) -> bool {
+        self.charts.has(inst, diff)
+    }
+
+    // all available charts, ordered by instrument then difficulty
+    pub fn available_charts(&self) -> impl Iterator<Item = Chart> + '_ {
+        self.charts.iter()
+    }
+}
@@ -0,0 +1,449 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{fs, io};
+use serde::{Deserialize, Serialize};
+use crate::songentry::SongEntry;
+use crate::util::{self, Encoding};
+
+/*
+   all known keys of the [song] section of song.ini
+   fields are named after their keys, unset keys are None
+*/
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SongIni {
+    // metadata
+    pub name: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<String>,
+    pub charter: Option<String>,
+    pub frets: Option<String>,
+    pub icon: Option<String>,
+    pub loading_phrase: Option<String>,
+    pub tags: Option<String>,
+    pub album_track: Option<i16>,
+    pub playlist: Option<String>,
+    pub sub_playlist: Option<String>,
+    pub playlist_track: Option<i16>,
+    pub rating: Option<i32>,
+
+    // timing, all in milliseconds
+    pub song_length: Option<i32>,
+    pub preview_start_time: Option<i32>,
+    pub preview_end_time: Option<i32>,
+    pub video_start_time: Option<i32>,
+    pub video_end_time: Option<i32>,
+    pub delay: Option<i32>,
+
+    // chart behaviour
+    pub hopo_frequency: Option<i32>,
+    pub eighthnote_hopo: Option<bool>,
+    pub multiplier_note: Option<i32>,
+    pub star_power_note: Option<i32>,
+    pub sustain_cutoff_threshold: Option<i32>,
+    pub modchart: Option<bool>,
+    pub pro_drums: Option<bool>,
+    pub five_lane_drums: Option<bool>,
+    pub drum_fallback_blue: Option<bool>,
+    pub end_events: Option<bool>,
+    pub sysex_slider: Option<bool>,
+    pub sysex_open_bass: Option<bool>,
+    pub sysex_high_hat_ctrl: Option<bool>,
+    pub sysex_rimshot: Option<bool>,
+    pub sysex_pro_slide: Option<bool>,
+
+    // difficulties
+    pub diff_band: Option<i8>,
+    pub diff_guitar: Option<i8>,
+    pub diff_guitar_coop: Option<i8>,
+    pub diff_rhythm: Option<i8>,
+    pub diff_bass: Option<i8>,
+    pub diff_drums: Option<i8>,
+    pub diff_drums_real: Option<i8>,
+    pub diff_drums_real_ps: Option<i8>,
+    pub diff_keys: Option<i8>,
+    pub diff_keys_real: Option<i8>,
+    pub diff_keys_real_ps: Option<i8>,
+    pub diff_guitar_real: Option<i8>,
+    pub diff_bass_real: Option<i8>,
+    pub diff_guitarghl: Option<i8>,
+    pub diff_guitar_coop_ghl: Option<i8>,
+    pub diff_rhythm_ghl: Option<i8>,
+    pub diff_bassghl: Option<i8>,
+    pub diff_vocals: Option<i8>,
+    pub diff_vocals_harm: Option<i8>,
+    pub diff_dance: Option<i8>,
+
+    // media
+    pub cover: Option<String>,
+    pub background: Option<String>,
+    pub video: Option<String>,
+    pub video_loop: Option<bool>,
+    pub banner_link_a: Option<String>,
+    pub link_name_a: Option<String>,
+    pub banner_link_b: Option<String>,
+    pub link_name_b: Option<String>,
+
+    // keys that are not known or could not be parsed, verbatim and in order
+    pub unknown: Vec<(String, String)>,
+}
+
+pub fn ini_get_bool(v: &str) -> Option<bool> {
+    match v.to_lowercase().as_str() {
+        "true" | "yes" | "t" | "y" | "1" | "on" => Some(true),
+        "false" | "no" | "f" | "n" | "0" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+// stores a parsed value, keeps the raw text if it is invalid
+fn set<T: FromStr>(field: &mut Option<T>, val: &str, unknown: &mut Vec<(String, String)>, key: &str) {
+    match val.parse::<T>() {
+        Ok(v) => *field = Some(v),
+        Err(_) => unknown.push((String::from(key), String::from(val))),
+    }
+}
+
+fn set_bool(field: &mut Option<bool>, val: &str, unknown: &mut Vec<(String, String)>, key: &str) {
+    match ini_get_bool(val) {
+        Some(v) => *field = Some(v),
+        None => unknown.push((String::from(key), String::from(val))),
+    }
+}
+
+impl SongIni {
+    /*
+       parses the [song] section of a song.ini
+       returns None if there is no [song] section with any keys
+       double keys will result in the last one being used
+    */
+    pub fn parse(raw_text: &str) -> Option<SongIni> {
+        let mut ini = SongIni::default();
+        let mut flag = false;
+        let mut section = String::new();
+
+        for line in raw_text.lines() {
+            let line = line.trim();
+
+            if line.starts_with('[') {
+                let end_pos = line.find(']').unwrap_or(line.len());
+                section = line.get(1..end_pos).unwrap_or("").to_lowercase();
+                continue;
+            }
+
+            // split key and value, ignore errors
+            let Some(arr) = line.split_once('=') else {
+                continue;
+            };
+
+            // properly format key and value, unknown keys keep their case
+            let raw_key = arr.0.trim();
+            let key = raw_key.to_lowercase();
+            let val = arr.1.trim();
+
+            if section != "song" {
+                continue;
+            }
+            flag = true;
+
+            let u = &mut ini.unknown;
+            match key.as_str() {
+                "name" => ini.name = Some(String::from(val)),
+                "artist" => ini.artist = Some(String::from(val)),
+                "album" => ini.album = Some(String::from(val)),
+                "genre" => ini.genre = Some(String::from(val)),
+                "year" => ini.year = Some(String::from(val)),
+                "charter" => ini.charter = Some(String::from(val)),
+                "frets" => ini.frets = Some(String::from(val)),
+                "icon" => ini.icon = Some(String::from(val)),
+                "loading_phrase" => ini.loading_phrase = Some(String::from(val)),
+                "tags" => ini.tags = Some(String::from(val)),
+                "album_track" | "track" => set(&mut ini.album_track, val, u, raw_key),
+                "playlist" => ini.playlist = Some(String::from(val)),
+                "sub_playlist" => ini.sub_playlist = Some(String::from(val)),
+                "playlist_track" => set(&mut ini.playlist_track, val, u, raw_key),
+                "rating" => set(&mut ini.rating, val, u, raw_key),
+
+                "song_length" => set(&mut ini.song_length, val, u, raw_key),
+                "preview_start_time" => set(&mut ini.preview_start_time, val, u, raw_key),
+                "preview_end_time" => set(&mut ini.preview_end_time, val, u, raw_key),
+                "video_start_time" => set(&mut ini.video_start_time, val, u, raw_key),
+                "video_end_time" => set(&mut ini.video_end_time, val, u, raw_key),
+                "delay" => set(&mut ini.delay, val, u, raw_key),
+
+                "hopo_frequency" => set(&mut ini.hopo_frequency, val, u, raw_key),
+                "eighthnote_hopo" => set_bool(&mut ini.eighthnote_hopo, val, u, raw_key),
+                "multiplier_note" => set(&mut ini.multiplier_note, val, u, raw_key),
+                "star_power_note" => set(&mut ini.star_power_note, val, u, raw_key),
+                "sustain_cutoff_threshold" => set(&mut ini.sustain_cutoff_threshold, val, u, raw_key),
+                "modchart" => set_bool(&mut ini.modchart, val, u, raw_key),
+                "pro_drums" => set_bool(&mut ini.pro_drums, val, u, raw_key),
+                "five_lane_drums" => set_bool(&mut ini.five_lane_drums, val, u, raw_key),
+                "drum_fallback_blue" => set_bool(&mut ini.drum_fallback_blue, val, u, raw_key),
+                "end_events" => set_bool(&mut ini.end_events, val, u, raw_key),
+                "sysex_slider" => set_bool(&mut ini.sysex_slider, val, u, raw_key),
+                "sysex_open_bass" => set_bool(&mut ini.sysex_open_bass, val, u, raw_key),
+                "sysex_high_hat_ctrl" => set_bool(&mut ini.sysex_high_hat_ctrl, val, u, raw_key),
+                "sysex_rimshot" => set_bool(&mut ini.sysex_rimshot, val, u, raw_key),
+                "sysex_pro_slide" => set_bool(&mut ini.sysex_pro_slide, val, u, raw_key),
+
+                "diff_band" => set(&mut ini.diff_band, val, u, raw_key),
+                "diff_guitar" => set(&mut ini.diff_guitar, val, u, raw_key),
+                "diff_guitar_coop" => set(&mut ini.diff_guitar_coop, val, u, raw_key),
+                "diff_rhythm" => set(&mut ini.diff_rhythm, val, u, raw_key),
+                "diff_bass" => set(&mut ini.diff_bass, val, u, raw_key),
+                "diff_drums" => set(&mut ini.diff_drums, val, u, raw_key),
+                "diff_drums_real" => set(&mut ini.diff_drums_real, val, u, raw_key),
+                "diff_drums_real_ps" => set(&mut ini.diff_drums_real_ps, val, u, raw_key),
+                "diff_keys" => set(&mut ini.diff_keys, val, u, raw_key),
+                "diff_keys_real" => set(&mut ini.diff_keys_real, val, u, raw_key),
+                "diff_keys_real_ps" => set(&mut ini.diff_keys_real_ps, val, u, raw_key),
+                "diff_guitar_real" => set(&mut ini.diff_guitar_real, val, u, raw_key),
+                "diff_bass_real" => set(&mut ini.diff_bass_real, val, u, raw_key),
+                "diff_guitarghl" => set(&mut ini.diff_guitarghl, val, u, raw_key),
+                "diff_guitar_coop_ghl" => set(&mut ini.diff_guitar_coop_ghl, val, u, raw_key),
+                "diff_rhythm_ghl" => set(&mut ini.diff_rhythm_ghl, val, u, raw_key),
+                "diff_bassghl" => set(&mut ini.diff_bassghl, val, u, raw_key),
+                "diff_vocals" => set(&mut ini.diff_vocals, val, u, raw_key),
+                "diff_vocals_harm" => set(&mut ini.diff_vocals_harm, val, u, raw_key),
+                "diff_dance" => set(&mut ini.diff_dance, val, u, raw_key),
+
+                "cover" => ini.cover = Some(String::from(val)),
+                "background" => ini.background = Some(String::from(val)),
+                "video" => ini.video = Some(String::from(val)),
+                "video_loop" => set_bool(&mut ini.video_loop, val, u, raw_key),
+                "banner_link_a" => ini.banner_link_a = Some(String::from(val)),
+                "link_name_a" => ini.link_name_a = Some(String::from(val)),
+                "banner_link_b" => ini.banner_link_b = Some(String::from(val)),
+                "link_name_b" => ini.link_name_b = Some(String::from(val)),
+
+                _ => u.push((String::from(raw_key), String::from(val))),
+            }
+        }
+
+        if flag {
+            Some(ini)
+        } else {
+            None
+        }
+    }
+
+    // copies everything the cache knows about into the song entry
+    pub fn apply(&self, song: &mut SongEntry) {
+        let text = [
+            self.name.as_ref(),
+            self.artist.as_ref(),
+            self.album.as_ref(),
+            self.genre.as_ref(),
+            self.year.as_ref(),
+            self.charter.as_ref().or(self.frets.as_ref()),
+        ];
+        for (m, v) in song.metadata.iter_mut().zip(text) {
+            if let Some(v) = v {
+                *m = v.clone();
+            }
+        }
+
+        let i = &mut song.intensities;
+        i.band = self.diff_band.unwrap_or(-1);
+        i.guitar = self.diff_guitar.unwrap_or(-1);
+        i.rhythm = self.diff_rhythm.unwrap_or(-1);
+        i.bass = self.diff_bass.unwrap_or(-1);
+        i.drums = self.diff_drums.unwrap_or(-1);
+        i.keys = self.diff_keys.unwrap_or(-1);
+        i.ghl_guitar = self.diff_guitarghl.unwrap_or(-1);
+        i.ghl_bass = self.diff_bassghl.unwrap_or(-1);
+
+        // fix intensities
+        i.guitar_coop = 0;
+        i.pro_drums = match self.diff_drums_real {
+            Some(v) if v != -1 => v,
+            _ => i.drums,
+        };
+
+        song.preview_start = self.preview_start_time.unwrap_or(-1);
+        if let Some(v) = &self.icon {
+            song.icon_name = v.to_lowercase();
+        }
+        song.playlist_track = self.playlist_track.unwrap_or(16000);
+        song.modchart = self.modchart.unwrap_or(false);
+        song.song_length = self.song_length.unwrap_or(0);
+        song.force_pro_drums = self.pro_drums.unwrap_or(false);
+        song.force_five_lane = self.five_lane_drums.unwrap_or(false);
+        if let Some(v) = &self.playlist {
+            song.top_level_playlist = v.to_lowercase();
+        }
+        if let Some(v) = &self.sub_playlist {
+            song.sub_playlist = v.to_lowercase();
+        }
+        song.album_track = self.album_track.unwrap_or(16000);
+    }
+}
+
+/*
+   song.ini kept as text so it can be edited and written back
+   comments, key order, unknown keys, line endings and encoding are preserved,
+   only the lines of edited keys are changed
+*/
+#[derive(Debug, Clone)]
+pub struct IniDocument {
+    lines: Vec<String>,
+    newline: &'static str,
+    trailing_newline: bool,
+    pub encoding: Encoding,
+}
+
+impl IniDocument {
+    pub fn parse(text: &str, encoding: Encoding) -> IniDocument {
+        IniDocument {
+            lines: text.lines().map(String::from).collect(),
+            newline: if text.contains("\r\n") { "\r\n" } else { "\n" },
+            trailing_newline: text.ends_with('\n'),
+            encoding,
+        }
+    }
+
+    pub fn load(p: &PathBuf) -> io::Result<IniDocument> {
+        let (text, encoding) = util::string_from_file_with_encoding(p)?;
+        Ok(IniDocument::parse(&text, encoding))
+    }
+
+    pub fn save(&self, p: &PathBuf) -> io::Result<()> {
+        fs::write(p, self.to_bytes())
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = self.lines.join(self.newline);
+        if self.trailing_newline {
+            out.push_str(self.newline);
+        }
+        out
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        util::string_to_bytes(&self.to_text(), self.encoding)
+    }
+
+    pub fn song_ini(&self) -> Option<SongIni> {
+        SongIni::parse(&self.to_text())
+    }
+
+    // section name of a line, if it starts one
+    fn section_of(line: &str) -> Option<String> {
+        let line = line.trim();
+        if !line.starts_with('[') {
+            return None;
+        }
+        let end_pos = line.find(']').unwrap_or(line.len());
+        Some(line.get(1..end_pos).unwrap_or("").to_lowercase())
+    }
+
+    // index of the last line setting the key in the [song] section
+    fn find(&self, key: &str) -> Option<usize> {
+        let mut section = String::new();
+        let mut found = None;
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Some(s) = IniDocument::section_of(line) {
+                section = s;
+                continue;
+            }
+            if section != "song" {
+                continue;
+            }
+            if let Some((k, _)) = line.split_once('=') {
+                if k.trim().eq_ignore_ascii_case(key) {
+                    found = Some(i);
+                }
+            }
+        }
+        found
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let i = self.find(key)?;
+        self.lines[i].split_once('=').map(|(_, v)| v.trim())
+    }
+
+    /*
+       sets a key in the [song] section
+       existing keys keep their spelling and spacing, new keys are added
+       after the last line of the section, which is created if missing
+    */
+    pub fn set(&mut self, key: &str, val: &str) {
+        if let Some(i) = self.find(key) {
+            let line = &self.lines[i];
+            let eq = line.find('=').unwrap();
+            let spacing = line[eq + 1..].len() - line[eq + 1..].trim_start().len();
+            let spacing = if spacing == 0 { "" } else { " " };
+            self.lines[i] = format!("{}{}{}", &line[..=eq], spacing, val);
+            return;
+        }
+
+        let mut section = String::new();
+        let mut last = None;
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Some(s) = IniDocument::section_of(line) {
+                section = s;
+                if section == "song" {
+                    last = Some(i);
+                }
+                continue;
+            }
+            if section == "song" && !line.trim().is_empty() {
+                last = Some(i);
+            }
+        }
+
+        let new_line = format!("{} = {}", key, val);
+        match last {
+            Some(i) => self.lines.insert(i + 1, new_line),
+            None => {
+                self.lines.push(String::from("[song]"));
+                self.lines.push(new_line);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_unknown_keys_verbatim() {
+        let ini = SongIni::parse("[Song]\nName = A\nMyCustomKey = 1\nDiff_Guitar = hard\n").unwrap();
+        assert_eq!(ini.name.as_deref(), Some("A"));
+        assert_eq!(
+            ini.unknown,
+            vec![
+                (String::from("MyCustomKey"), String::from("1")),
+                (String::from("Diff_Guitar"), String::from("hard")),
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_a_song_section() {
+        assert!(SongIni::parse("").is_none());
+        assert!(SongIni::parse("[other]\nname = A\n").is_none());
+    }
+
+    #[test]
+    fn survives_broken_section_headers() {
+        assert!(SongIni::parse("[\nname = A\n").is_none());
+        assert!(SongIni::parse("[é\nname = A\n").is_none());
+        assert_eq!(SongIni::parse("[song\nname = A\n").unwrap().name.as_deref(), Some("A"));
+        assert_eq!(SongIni::parse("[é]\n[song]\nname = B\n").unwrap().name.as_deref(), Some("B"));
+    }
+
+    #[test]
+    fn document_round_trips() {
+        let text = "[song]\r\nname = A\r\nCustom = x\r\n";
+        let mut doc = IniDocument::parse(text, Encoding::Utf8);
+        assert_eq!(doc.to_text(), text);
+        doc.set("name", "B");
+        assert_eq!(doc.to_text(), "[song]\r\nname = B\r\nCustom = x\r\n");
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, RgbaImage};
+use serde::Serialize;
+use crate::art;
+use crate::config::{ArtConfig, SpriteConfig};
+use crate::songentry::SongEntry;
+use crate::util;
+
+/*
+   where the thumbnail of every song is, written as index.json next to the sheets
+   the thumbnail is centered in its cell, width and height are its own size
+*/
+#[derive(Serialize, Debug, Default)]
+pub struct SpriteIndex {
+    pub size: u32, // of every cell
+    pub sheets: Vec<Sheet>,
+    pub songs: BTreeMap<String, Sprite>, // by checksum
+}
+
+#[derive(Serialize, Debug)]
+pub struct Sheet {
+    pub path: String, // relative to the sprite folder
+    pub playlist: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Sprite {
+    pub sheet: usize, // position in SpriteIndex::sheets
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// sheets of an earlier run, there might have been more of them
+fn remove_sheets(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let p = entry?.path();
+        let name = p.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with("sheet_") && p.is_file() {
+            fs::remove_file(&p)?;
+        }
+    }
+    Ok(())
+}
+
+// the scaled art closest to the cell size, scaled down to fit if needed
+fn thumbnail(song: &SongEntry, art_dir: &Path, size: u32) -> Result<Option<DynamicImage>, String> {
+    let Some(a) = &song.art else {
+        return Ok(None);
+    };
+    let best = a
+        .images
+        .iter()
+        .filter(|i| i.size >= size)
+        .min_by_key(|i| i.size)
+        .or_else(|| a.images.iter().max_by_key(|i| i.size));
+    let Some(best) = best else {
+        return Ok(None);
+    };
+
+    let p = art_dir.join(&best.path);
+    let img = image::open(&p).map_err(|e| format!("{}: {}", p.display(), e))?;
+    let (w, h) = art::fit(img.width(), img.height(), size);
+    if (w, h) == (img.width(), img.height()) {
+        Ok(Some(img))
+    } else {
+        Ok(Some(img.resize_exact(w, h, FilterType::Triangle)))
+    }
+}
+
+/*
+   packs the album art thumbnails of every playlist into sheets
+   songs are grouped by the playlist the game shows them in and keep the
+   playlist order, a playlist gets as many sheets as it needs
+   songs without art are left out
+*/
+pub fn write_sprites(
+    songs: &[SongEntry],
+    art_dir: &Path,
+    art: &ArtConfig,
+    config: &SpriteConfig,
+    dir: &Path,
+) -> Result<SpriteIndex, String> {
+    config.check()?;
+    let (size, columns, rows) = (config.size, config.columns, config.rows);
+
+    let mut playlists: BTreeMap<&str, Vec<&SongEntry>> = BTreeMap::new();
+    for song in songs.iter().filter(|s| s.art.is_some()) {
+        playlists.entry(&song.metadata[6]).or_default().push(song);
+    }
+
+    let mut index = SpriteIndex {
+        size,
+        ..Default::default()
+    };
+    let mut done = HashSet::new();
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    remove_sheets(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+
+    for (playlist, mut list) in playlists {
+        list.sort_by(|a, b| (a.playlist_track, &a.folder_path).cmp(&(b.playlist_track, &b.folder_path)));
+
+        for chunk in list.chunks((columns * rows) as usize) {
+            let used_rows = (chunk.len() as u32).div_ceil(columns);
+            let used_columns = (chunk.len() as u32).min(columns);
+            let mut sheet = RgbaImage::new(used_columns * size, used_rows * size);
+            let n = index.sheets.len();
+
+            for (i, song) in chunk.iter().enumerate() {
+                // a song listed twice keeps its first cell
+                if done.contains(&song.checksum) {
+                    continue;
+                }
+                let img = match thumbnail(song, art_dir, size) {
+                    Ok(Some(img)) => img,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        log::warn!("{}: {}", song.folder_path, e);
+                        continue;
+                    }
+                };
+                let (x, y) = (i as u32 % columns * size, i as u32 / columns * size);
+                let (w, h) = (img.width(), img.height());
+                imageops::overlay(&mut sheet, &img.to_rgba8(), (x + (size - w) / 2) as i64, (y + (size - h) / 2) as i64);
+                done.insert(song.checksum);
+                index.songs.insert(
+                    util::checksum_hex(&song.checksum),
+                    Sprite {
+                        sheet: n,
+                        x,
+                        y,
+                        width: w,
+                        height: h,
+                    },
+                );
+            }
+
+            let (width, height) = sheet.dimensions();
+            let path = format!("sheet_{:04}.{}", n, config.format.extension());
+            let data = art::encode(&DynamicImage::ImageRgba8(sheet), config.format, art.jpeg_quality)?;
+            let p = dir.join(&path);
+            util::write_atomic(&p, &data).map_err(|e| format!("{}: {}", p.display(), e))?;
+            index.sheets.push(Sheet {
+                path,
+                playlist: String::from(playlist),
+                width,
+                height,
+            });
+        }
+    }
+
+    let p = dir.join("index.json");
+    let json = serde_json::to_string_pretty(&index).unwrap();
+    util::write_atomic(&p, json.as_bytes()).map_err(|e| format!("{}: {}", p.display(), e))?;
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::art::ArtFormat;
+    use crate::writer::tests::{songs, temp_path};
+
+    // songs with a differently sized and colored cover each
+    fn songs_with_art(art_dir: &Path, config: &ArtConfig) -> Vec<SongEntry> {
+        let mut songs = songs();
+        for (i, song) in songs.iter_mut().enumerate() {
+            let img = RgbaImage::from_pixel(40 + 10 * i as u32, 20, image::Rgba([50 * i as u8, 0, 0, 255]));
+            let data = art::encode(&DynamicImage::ImageRgba8(img), ArtFormat::Webp, 90).unwrap();
+            song.art = Some(art::make_art("album.webp", &data, config, art_dir).unwrap());
+        }
+        songs[2].art = None;
+        songs
+    }
+
+    #[test]
+    fn packs_every_playlist_into_sheets() {
+        let dir = temp_path("sprites");
+        let art = ArtConfig {
+            sizes: vec![32],
+            formats: vec![ArtFormat::Webp],
+            ..Default::default()
+        };
+        let songs = songs_with_art(&dir.join("art"), &art);
+        let config = SpriteConfig {
+            size: 16,
+            columns: 2,
+            rows: 1,
+            format: ArtFormat::Webp,
+        };
+        let out = dir.join("sheets");
+        fs::create_dir_all(&out).unwrap();
+        fs::write(out.join("sheet_0009.webp"), b"stale").unwrap();
+
+        let index = write_sprites(&songs, &dir.join("art"), &art, &config, &out).unwrap();
+        let mut files: Vec<String> = fs::read_dir(&out)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        let _ = fs::remove_dir_all(&dir);
+
+        // metal has songs 1 and 3, rock songs 0 and 4 as song 2 has no art
+        let playlists: Vec<&str> = index.sheets.iter().map(|s| s.playlist.as_str()).collect();
+        assert_eq!(playlists, ["metal", "rock"]);
+        assert_eq!(files, ["index.json", "sheet_0000.webp", "sheet_0001.webp"]);
+        assert_eq!((index.sheets[1].width, index.sheets[1].height), (32, 16));
+
+        let sprite = &index.songs[&util::checksum_hex(&[5; 16])];
+        assert_eq!((sprite.sheet, sprite.x, sprite.y), (1, 16, 0));
+        assert_eq!((sprite.width, sprite.height), (16, 4));
+        assert!(!index.songs.contains_key(&util::checksum_hex(&[3; 16])));
+    }
+
+    #[test]
+    fn rejects_broken_configs() {
+        let dir = temp_path("sprites-broken");
+        for (columns, rows, size) in [(0, 1, 16), (1, 0, 16), (1, 1, 0), (2, 2, 10000)] {
+            let config = SpriteConfig {
+                size,
+                columns,
+                rows,
+                format: ArtFormat::Webp,
+            };
+            assert!(write_sprites(&songs(), &dir, &ArtConfig::default(), &config, &dir).is_err());
+        }
+        assert!(!dir.exists());
+    }
+}
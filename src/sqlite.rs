@@ -0,0 +1,328 @@
+use std::path::Path;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, Transaction};
+use crate::songentry::{SongEntry, METADATA_NAMES};
+use crate::{text, util};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS songs (
+    checksum TEXT PRIMARY KEY,
+    folder_path TEXT NOT NULL,
+    chart_name TEXT NOT NULL,
+    name TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    album TEXT NOT NULL,
+    genre TEXT NOT NULL,
+    year TEXT NOT NULL,
+    charter TEXT NOT NULL,
+    playlist TEXT NOT NULL,
+    name_plain TEXT NOT NULL,
+    artist_plain TEXT NOT NULL,
+    album_plain TEXT NOT NULL,
+    genre_plain TEXT NOT NULL,
+    year_plain TEXT NOT NULL,
+    charter_plain TEXT NOT NULL,
+    playlist_plain TEXT NOT NULL,
+    name_sort TEXT NOT NULL,
+    artist_sort TEXT NOT NULL,
+    album_sort TEXT NOT NULL,
+    genre_sort TEXT NOT NULL,
+    year_sort TEXT NOT NULL,
+    charter_sort TEXT NOT NULL,
+    playlist_sort TEXT NOT NULL,
+    album_track INTEGER NOT NULL,
+    playlist_track INTEGER NOT NULL,
+    charts INTEGER NOT NULL,
+    date_added INTEGER NOT NULL,
+    force_five_lane INTEGER NOT NULL,
+    force_pro_drums INTEGER NOT NULL,
+    icon_name TEXT NOT NULL,
+    is_enc INTEGER NOT NULL,
+    lyrics INTEGER NOT NULL,
+    modchart INTEGER NOT NULL,
+    preview_start INTEGER NOT NULL,
+    song_length INTEGER NOT NULL,
+    sub_playlist TEXT NOT NULL,
+    top_level_playlist TEXT NOT NULL,
+    video_background INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS charts (
+    checksum TEXT NOT NULL REFERENCES songs(checksum) ON DELETE CASCADE,
+    instrument TEXT NOT NULL,
+    difficulty TEXT NOT NULL,
+    PRIMARY KEY (checksum, instrument, difficulty)
+);
+CREATE TABLE IF NOT EXISTS intensities (
+    checksum TEXT NOT NULL REFERENCES songs(checksum) ON DELETE CASCADE,
+    instrument TEXT NOT NULL,
+    intensity INTEGER NOT NULL,
+    PRIMARY KEY (checksum, instrument)
+);
+CREATE TABLE IF NOT EXISTS files (
+    checksum TEXT NOT NULL REFERENCES songs(checksum) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    PRIMARY KEY (checksum, name)
+);
+";
+
+// created after migrate, older songs tables lack the sort columns
+const INDEXES: &str = "
+CREATE INDEX IF NOT EXISTS songs_sort ON songs (artist_sort, name_sort);
+";
+
+const DROP: &str = "
+DROP TABLE IF EXISTS files;
+DROP TABLE IF EXISTS intensities;
+DROP TABLE IF EXISTS charts;
+DROP TABLE IF EXISTS songs;
+";
+
+// columns of the songs table in insert order
+const SONG_COLUMNS: [&str; 39] = [
+    "checksum",
+    "folder_path",
+    "chart_name",
+    "name",
+    "artist",
+    "album",
+    "genre",
+    "year",
+    "charter",
+    "playlist",
+    "name_plain",
+    "artist_plain",
+    "album_plain",
+    "genre_plain",
+    "year_plain",
+    "charter_plain",
+    "playlist_plain",
+    "name_sort",
+    "artist_sort",
+    "album_sort",
+    "genre_sort",
+    "year_sort",
+    "charter_sort",
+    "playlist_sort",
+    "album_track",
+    "playlist_track",
+    "charts",
+    "date_added",
+    "force_five_lane",
+    "force_pro_drums",
+    "icon_name",
+    "is_enc",
+    "lyrics",
+    "modchart",
+    "preview_start",
+    "song_length",
+    "sub_playlist",
+    "top_level_playlist",
+    "video_background",
+];
+
+/*
+   adds the plain and sort columns to a songs table from before they existed
+   and fills them from the metadata columns
+*/
+fn migrate(tx: &Transaction) -> rusqlite::Result<()> {
+    let columns: Vec<String> = tx
+        .prepare("SELECT name FROM pragma_table_info('songs')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    let missing: Vec<String> = METADATA_NAMES
+        .iter()
+        .flat_map(|m| [format!("{}_plain", m), format!("{}_sort", m)])
+        .filter(|c| !columns.contains(c))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    log::info!("adding {} columns to the songs table", missing.len());
+    for c in &missing {
+        tx.execute_batch(&format!("ALTER TABLE songs ADD COLUMN {} TEXT NOT NULL DEFAULT ''", c))?;
+    }
+
+    let rows: Vec<(String, SongEntry)> = tx
+        .prepare(&format!("SELECT checksum, {} FROM songs", METADATA_NAMES.join(", ")))?
+        .query_map([], |row| {
+            let mut song = SongEntry::default();
+            for (i, m) in song.metadata.iter_mut().enumerate() {
+                *m = row.get(i + 1)?;
+            }
+            Ok((row.get(0)?, song))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    let sets: Vec<String> = METADATA_NAMES
+        .iter()
+        .enumerate()
+        .flat_map(|(i, m)| [format!("{}_plain = ?{}", m, i + 2), format!("{}_sort = ?{}", m, i + 9)])
+        .collect();
+    let mut update = tx.prepare(&format!("UPDATE songs SET {} WHERE checksum = ?1", sets.join(", ")))?;
+    for (checksum, song) in rows {
+        let mut values = vec![Value::from(checksum)];
+        values.extend(text::plain_metadata(&song).map(Value::from));
+        values.extend(text::sort_metadata(&song).map(Value::from));
+        update.execute(params_from_iter(values))?;
+    }
+    Ok(())
+}
+
+fn song_values(song: &SongEntry) -> Vec<Value> {
+    let mut out: Vec<Value> = vec![
+        util::checksum_hex(&song.checksum).into(),
+        song.folder_path.clone().into(),
+        song.chart_name.clone().into(),
+    ];
+    out.extend(song.metadata.iter().map(|m| Value::from(m.clone())));
+    out.extend(text::plain_metadata(song).map(Value::from));
+    out.extend(text::sort_metadata(song).map(Value::from));
+    out.extend([
+        song.album_track.into(),
+        song.playlist_track.into(),
+        song.charts.0.into(),
+        song.date_added.into(),
+        song.force_five_lane.into(),
+        song.force_pro_drums.into(),
+        song.icon_name.clone().into(),
+        song.is_enc.into(),
+        song.lyrics.into(),
+        song.modchart.into(),
+        song.preview_start.into(),
+        song.song_length.into(),
+        song.sub_playlist.clone().into(),
+        song.top_level_playlist.clone().into(),
+        song.video_background.into(),
+    ]);
+    out
+}
+
+fn insert_song(tx: &Transaction, song: &SongEntry) -> rusqlite::Result<()> {
+    let checksum = util::checksum_hex(&song.checksum);
+
+    let placeholders: Vec<String> = (1..=SONG_COLUMNS.len()).map(|i| format!("?{}", i)).collect();
+    let updates: Vec<String> = SONG_COLUMNS[1..]
+        .iter()
+        .map(|c| format!("{0} = excluded.{0}", c))
+        .collect();
+    let sql = format!(
+        "INSERT INTO songs ({}) VALUES ({}) ON CONFLICT(checksum) DO UPDATE SET {}",
+        SONG_COLUMNS.join(", "),
+        placeholders.join(", "),
+        updates.join(", ")
+    );
+    tx.prepare_cached(&sql)?
+        .execute(params_from_iter(song_values(song)))?;
+
+    // child rows are replaced as a whole on update
+    tx.execute("DELETE FROM charts WHERE checksum = ?1", [&checksum])?;
+    tx.execute("DELETE FROM intensities WHERE checksum = ?1", [&checksum])?;
+    tx.execute("DELETE FROM files WHERE checksum = ?1", [&checksum])?;
+
+    for chart in song.available_charts() {
+        tx.execute(
+            "INSERT INTO charts VALUES (?1, ?2, ?3)",
+            params![checksum, chart.instrument.name(), chart.difficulty.name()],
+        )?;
+    }
+    for (inst, intensity) in song.intensities.iter() {
+        if intensity != -1 {
+            tx.execute(
+                "INSERT INTO intensities VALUES (?1, ?2, ?3)",
+                params![checksum, inst.name(), intensity],
+            )?;
+        }
+    }
+
+    // the cloud format lists every file of the folder after the chart name
+    for name in song.chart_name.split('\n') {
+        tx.execute(
+            "INSERT OR IGNORE INTO files VALUES (?1, ?2)",
+            params![checksum, name],
+        )?;
+    }
+
+    Ok(())
+}
+
+/*
+   writes all songs into an SQLite database
+   without update the tables are recreated from scratch,
+   with update existing songs are upserted by checksum
+*/
+pub fn export_sqlite(songs: &[SongEntry], p: &Path, update: bool) -> rusqlite::Result<()> {
+    let mut conn = Connection::open(p)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+
+    let tx = conn.transaction()?;
+    if !update {
+        tx.execute_batch(DROP)?;
+    }
+    tx.execute_batch(SCHEMA)?;
+    migrate(&tx)?;
+    tx.execute_batch(INDEXES)?;
+    for song in songs {
+        insert_song(&tx, song)?;
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::writer::tests::{songs, temp_path};
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn exports_and_updates_songs() {
+        let p = temp_path("export.db");
+        let _ = std::fs::remove_file(&p);
+        export_sqlite(&songs()[..3], &p, false).unwrap();
+
+        let mut changed = songs()[2..].to_vec();
+        changed[0].metadata[0] = String::from("<b>Renamed</b>");
+        export_sqlite(&changed, &p, true).unwrap();
+        let conn = Connection::open(&p).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM songs"), 5);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM charts"), 5);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM files"), 5 * 4);
+        let (name, plain): (String, String) = conn
+            .query_row("SELECT name, name_plain FROM songs WHERE checksum = ?1", [util::checksum_hex(&[3; 16])], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!((name.as_str(), plain.as_str()), ("<b>Renamed</b>", "Renamed"));
+        drop(conn);
+
+        export_sqlite(&songs()[..1], &p, false).unwrap();
+        let conn = Connection::open(&p).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM songs"), 1);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM files"), 4);
+        drop(conn);
+        let _ = std::fs::remove_file(&p);
+    }
+
+    #[test]
+    fn migrates_tables_without_sort_columns() {
+        let p = temp_path("migrate.db");
+        let _ = std::fs::remove_file(&p);
+        export_sqlite(&songs(), &p, false).unwrap();
+        {
+            let conn = Connection::open(&p).unwrap();
+            conn.execute_batch("DROP INDEX songs_sort;").unwrap();
+            for name in METADATA_NAMES {
+                let sql = format!("ALTER TABLE songs DROP COLUMN {0}_plain; ALTER TABLE songs DROP COLUMN {0}_sort;", name);
+                conn.execute_batch(&sql).unwrap();
+            }
+        }
+
+        export_sqlite(&songs()[..1], &p, true).unwrap();
+        let conn = Connection::open(&p).unwrap();
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM songs WHERE artist_sort = ''"), 0);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM songs WHERE artist_sort = 'dragonforce'"), 2);
+        drop(conn);
+        let _ = std::fs::remove_file(&p);
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use crate::query::{self, Query};
+use crate::songentry::SongEntry;
+use crate::util;
+
+pub enum Selection {
+    Checksums(Vec<[u8; 16]>),
+    Paths(Vec<String>),
+    Query(Query),
+}
+
+// reads a list file, one item per line, blank lines and # comments are ignored
+fn list_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+}
+
+impl Selection {
+    pub fn checksums_from_str(text: &str) -> Result<Selection, String> {
+        let mut out = vec![];
+        for line in list_lines(text) {
+            out.push(util::checksum_from_hex(line).ok_or_else(|| format!("invalid checksum \"{}\"", line))?);
+        }
+        Ok(Selection::Checksums(out))
+    }
+
+    pub fn paths_from_str(text: &str) -> Selection {
+        Selection::Paths(list_lines(text).map(String::from).collect())
+    }
+}
+
+/*
+   picks the selected songs out of a full song list, keeping their order
+   returns the songs and every checksum or path that was not found
+   writing the result with writer::write_cache rebuilds the metadata
+   tables with only the entries still in use
+*/
+pub fn select(songs: Vec<SongEntry>, sel: &Selection) -> (Vec<SongEntry>, Vec<String>) {
+    match sel {
+        Selection::Query(q) => (query::filter(songs, q), vec![]),
+        Selection::Checksums(list) => {
+            let wanted: HashSet<[u8; 16]> = list.iter().copied().collect();
+            let out: Vec<SongEntry> = songs.into_iter().filter(|s| wanted.contains(&s.checksum)).collect();
+            let found: HashSet<[u8; 16]> = out.iter().map(|s| s.checksum).collect();
+            let missing = list
+                .iter()
+                .filter(|c| !found.contains(*c))
+                .map(util::checksum_hex)
+                .collect();
+            (out, missing)
+        }
+        Selection::Paths(list) => {
+            let wanted: HashSet<String> = list.iter().map(|p| util::normalize_path(p)).collect();
+            let out: Vec<SongEntry> = songs
+                .into_iter()
+                .filter(|s| wanted.contains(&util::normalize_path(&s.folder_path)))
+                .collect();
+            let found: HashSet<String> = out.iter().map(|s| util::normalize_path(&s.folder_path)).collect();
+            let missing = list
+                .iter()
+                .filter(|p| !found.contains(&util::normalize_path(p)))
+                .cloned()
+                .collect();
+            (out, missing)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader;
+    use crate::writer::{self, tests::songs};
+    use std::io::Cursor;
+
+    fn names(songs: &[SongEntry]) -> Vec<&str> {
+        songs.iter().map(|s| s.metadata[0].as_str()).collect()
+    }
+
+    #[test]
+    fn selects_by_checksum_in_song_order() {
+        let (a, b) = (util::checksum_hex(&[4; 16]), util::checksum_hex(&[2; 16]));
+        let text = format!("# picks\n{}\n\n{}\n{}\n", a, b, "ff".repeat(16));
+        let sel = Selection::checksums_from_str(&text).unwrap();
+        let (out, missing) = select(songs(), &sel);
+        assert_eq!(names(&out), ["Song 1", "Song 3"]);
+        assert_eq!(missing, ["ff".repeat(16)]);
+        assert!(Selection::checksums_from_str("abc\n").is_err());
+    }
+
+    #[test]
+    fn selects_by_normalized_path() {
+        let path = songs()[2].folder_path.to_uppercase() + "/";
+        let sel = Selection::paths_from_str(&format!("{}\n/songs/missing\n", path));
+        let (out, missing) = select(songs(), &sel);
+        assert_eq!(names(&out), ["Song 2"]);
+        assert_eq!(missing, ["/songs/missing"]);
+    }
+
+    #[test]
+    fn subsets_drop_unused_metadata() {
+        let sel = Selection::Query(Query::parse("genre:metal").unwrap());
+        let (out, missing) = select(songs(), &sel);
+        assert!(missing.is_empty());
+
+        let mut data = vec![];
+        writer::write_cache(out, &mut data).unwrap();
+        let mut cache = reader::IndexedCache::new(Cursor::new(data)).unwrap();
+        assert_eq!(cache.header().lists[3], ["Metal"]);
+        assert_eq!(names(&[cache.get(0).unwrap().unwrap(), cache.get(1).unwrap().unwrap()]), ["Song 1", "Song 4"]);
+    }
+}
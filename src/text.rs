@@ -0,0 +1,99 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use crate::songentry::SongEntry;
+
+// rich text tags understood by Clone Hero, the ones of Unity TextMesh Pro
+const TAG_NAMES: &[&str] = &[
+    "align", "allcaps", "alpha", "b", "br", "color", "cspace", "font", "font-weight", "gradient",
+    "i", "indent", "line-height", "line-indent", "link", "lowercase", "margin", "mark", "material", "mspace",
+    "nobr", "noparse", "page", "pos", "quad", "rotate", "s", "size", "smallcaps", "space",
+    "sprite", "strikethrough", "style", "sub", "sup", "u", "uppercase", "voffset", "width",
+];
+
+// matches the inside of a rich text tag like "b", "/color" or "size=20"
+fn is_tag(inner: &str) -> bool {
+    let name = inner.strip_prefix('/').unwrap_or(inner);
+    let name = name.split(['=', ' ']).next().unwrap_or("");
+    TAG_NAMES.iter().any(|t| t.eq_ignore_ascii_case(name))
+}
+
+/*
+   removes Clone Hero rich text tags like <color=#FF0000>, <b> or <size=20>
+   anything else between angle brackets is kept as is
+*/
+pub fn strip_tags(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find(['<', '>']) {
+            Some(end) if after.as_bytes()[end] == b'>' && is_tag(&after[..end]) => {
+                rest = &after[end + 1..];
+            }
+            _ => {
+                out.push('<');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+
+    out.trim().to_string()
+}
+
+/*
+   key for sorting and searching
+   no tags, no diacritics, lowercase, single spaces and without a leading "the "
+*/
+pub fn sort_key(s: &str) -> String {
+    let plain: String = strip_tags(s)
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase();
+    let plain = plain.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    match plain.strip_prefix("the ") {
+        Some(rest) if !rest.is_empty() => String::from(rest),
+        _ => plain,
+    }
+}
+
+// metadata without rich text tags, for display in plain text
+pub fn plain_metadata(song: &SongEntry) -> [String; 7] {
+    song.metadata.clone().map(|m| strip_tags(&m))
+}
+
+pub fn sort_metadata(song: &SongEntry) -> [String; 7] {
+    song.metadata.clone().map(|m| sort_key(&m))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_rich_text_tags() {
+        assert_eq!(strip_tags("<color=#FF0000>Red</color> <b>Song</b>"), "Red Song");
+        assert_eq!(strip_tags("<size=20>Big</size>"), "Big");
+        assert_eq!(strip_tags("<B>Loud</B>"), "Loud");
+        assert_eq!(strip_tags("<sprite name=\"x\">Icon"), "Icon");
+    }
+
+    #[test]
+    fn keeps_other_brackets() {
+        assert_eq!(strip_tags("Song <Demo>"), "Song <Demo>");
+        assert_eq!(strip_tags("<Live> at Wembley"), "<Live> at Wembley");
+        assert_eq!(strip_tags("1 < 2 > 0"), "1 < 2 > 0");
+        assert_eq!(strip_tags("a <b"), "a <b");
+    }
+
+    #[test]
+    fn sort_key_folds_text() {
+        assert_eq!(sort_key("The  <i>Beatles</i>"), "beatles");
+        assert_eq!(sort_key("Motörhead"), "motorhead");
+        assert_eq!(sort_key("The"), "the");
+    }
+}
@@ -1,8 +1,14 @@
-use std::io::Cursor;
 use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::{fs::File, io::Read, path::PathBuf};
-use byteorder::{LittleEndian, BigEndian, ReadBytesExt};
+use std::{fs::{self, File}, io, path::{Path, PathBuf}};
+use serde::{de, Deserialize, Deserializer};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Encoding {
+    Utf16Le,
+    Utf16Be,
+    Utf8Bom,
+    Utf8,
+}
 
 /*
    helper function to decode various text formats
@@ -11,61 +17,158 @@ use byteorder::{LittleEndian, BigEndian, ReadBytesExt};
    UTF-16 BE
    UTF-8 BOM
    UTF-8
+   input too short for a byte order mark is read as UTF-8
 */
-fn string_internal_reader<R: Read + Seek>(f: &mut R) -> String {
-    let mut buf = [0u8; 3];
-    f.read_exact(&mut buf).unwrap();
-
-    // UTF-16 BE
-    if buf[0] == 0xFE && buf[1] == 0xFF {
-        f.seek(SeekFrom::Start(2)).unwrap();
-
-        let mut buffer = vec![];
-        loop {
-            let num = f.read_u16::<BigEndian>();
-            if num.is_err() {
-                break;
+fn string_internal_reader(b: &[u8]) -> (String, Encoding) {
+    let utf16 = |b: &[u8], from: fn([u8; 2]) -> u16| -> String {
+        let units: Vec<u16> = b.chunks_exact(2).map(|c| from([c[0], c[1]])).collect();
+        String::from_utf16_lossy(&units)
+    };
+
+    match b {
+        // UTF-16 BE
+        [0xFE, 0xFF, rest @ ..] => (utf16(rest, u16::from_be_bytes), Encoding::Utf16Be),
+        // UTF-16 LE
+        [0xFF, 0xFE, rest @ ..] => (utf16(rest, u16::from_le_bytes), Encoding::Utf16Le),
+        // UTF-8 BOM
+        [0xEF, 0xBB, 0xBF, rest @ ..] => (String::from_utf8_lossy(rest).to_string(), Encoding::Utf8Bom),
+        // assume UTF-8
+        _ => (String::from_utf8_lossy(b).to_string(), Encoding::Utf8),
+    }
+}
+pub fn string_from_file(p: &PathBuf) -> io::Result<String> {
+    Ok(string_from_file_with_encoding(p)?.0)
+}
+pub fn string_from_file_with_encoding(p: &PathBuf) -> io::Result<(String, Encoding)> {
+    Ok(string_internal_reader(&fs::read(p)?))
+}
+pub fn string_from_bytes(b: &[u8]) -> String {
+    string_internal_reader(b).0
+}
+
+// encode text the same way it was read, including the byte order mark
+pub fn string_to_bytes(s: &str, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Utf16Le => {
+            let mut out = vec![0xFF, 0xFE];
+            for c in s.encode_utf16() {
+                out.extend_from_slice(&c.to_le_bytes());
             }
-            buffer.push(num.unwrap());
+            out
         }
+        Encoding::Utf16Be => {
+            let mut out = vec![0xFE, 0xFF];
+            for c in s.encode_utf16() {
+                out.extend_from_slice(&c.to_be_bytes());
+            }
+            out
+        }
+        Encoding::Utf8Bom => {
+            let mut out = vec![0xEF, 0xBB, 0xBF];
+            out.extend_from_slice(s.as_bytes());
+            out
+        }
+        Encoding::Utf8 => s.as_bytes().to_vec(),
+    }
+}
 
-        String::from_utf16_lossy(&buffer)
+// lowercase hex representation of a song checksum
+pub fn checksum_hex(c: &[u8; 16]) -> String {
+    c.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-    // UTF-16 LE
-    } else if buf[0] == 0xFF && buf[1] == 0xFE {
-        //let len = f.seek(SeekFrom::End(0)).unwrap() - 2;
-        f.seek(SeekFrom::Start(2)).unwrap();
+// parse a checksum from its hex representation
+pub fn checksum_from_hex(s: &str) -> Option<[u8; 16]> {
+    if s.len() != 32 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 16];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
 
-        let mut buffer = vec![];
-        loop {
-            let num = f.read_u16::<LittleEndian>();
-            if num.is_err() {
-                break;
-            }
-            buffer.push(num.unwrap());
-        }
+// accepts a checksum as either a byte array or a hex string
+pub fn deserialize_checksum<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 16], D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Checksum {
+        Bytes([u8; 16]),
+        Hex(String),
+    }
+
+    match Checksum::deserialize(d)? {
+        Checksum::Bytes(b) => Ok(b),
+        Checksum::Hex(s) => checksum_from_hex(&s)
+            .ok_or_else(|| de::Error::custom(format!("invalid checksum \"{}\"", s))),
+    }
+}
 
-        String::from_utf16_lossy(&buffer)
+// folder paths are compared case insensitive and without trailing separators
+pub fn normalize_path(p: &str) -> String {
+    p.trim().trim_end_matches(['/', '\\']).to_lowercase()
+}
 
-    // UTF-8 BOM
-    } else if buf[0] == 0xEF && buf[1] == 0xBB && buf[2] == 0xBF {
-        let mut buffer = vec![];
-        f.read_to_end(&mut buffer).unwrap();
-        String::from_utf8_lossy(&buffer).to_string()
+// file next to p that is written first and then renamed over p
+pub fn temp_path(p: &Path) -> PathBuf {
+    let mut name = p.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    p.with_file_name(name)
+}
 
-    // assume UTF-8
-    } else {
-        f.seek(SeekFrom::Start(0)).unwrap();
-        let mut buffer = vec![];
-        f.read_to_end(&mut buffer).unwrap();
-        String::from_utf8_lossy(&buffer).to_string()
+// replaces the file in one step, readers see either the old or the new content
+pub fn write_atomic(p: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(p);
+    let result = (|| {
+        let mut f = File::create(&tmp)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        fs::rename(&tmp, p)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
     }
+    result
 }
-pub fn string_from_file(p: &PathBuf) -> String {
-    let mut f = File::open(p).unwrap();
-    string_internal_reader(&mut f)
+
+// counts and lengths come from the file, a broken one should not allocate gigabytes
+pub const PREALLOCATE: usize = 1 << 16;
+
+pub fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
 }
-pub fn string_from_bytes(b: &[u8]) -> String {
-    let mut c = Cursor::new(b);
-    string_internal_reader(&mut c)
+
+// reads len bytes, memory grows with what is actually read instead of with len
+pub fn read_bytes<R: Read>(f: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity((len as usize).min(PREALLOCATE));
+    f.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("expected {} bytes, got {}", len, buf.len())));
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_short_input() {
+        assert_eq!(string_internal_reader(b""), (String::new(), Encoding::Utf8));
+        assert_eq!(string_internal_reader(b"a"), (String::from("a"), Encoding::Utf8));
+        assert_eq!(string_internal_reader(&[0xFF, 0xFE]), (String::new(), Encoding::Utf16Le));
+        assert_eq!(string_internal_reader(&[0xEF, 0xBB]).1, Encoding::Utf8);
+    }
+
+    #[test]
+    fn round_trips_every_encoding() {
+        for encoding in [Encoding::Utf16Le, Encoding::Utf16Be, Encoding::Utf8Bom, Encoding::Utf8] {
+            let bytes = string_to_bytes("[song]\nname = Motörhead\n", encoding);
+            assert_eq!(
+                string_internal_reader(&bytes),
+                (String::from("[song]\nname = Motörhead\n"), encoding)
+            );
+        }
+    }
 }
@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use crate::config::ScanConfig;
+use crate::report::ScanReport;
+use crate::scanner::{self, ScannedSong};
+use crate::songentry::SongEntry;
+
+/*
+   a scanned library that is kept up to date with the song roots
+   songs are ordered by the folder or archive they were read from
+*/
+pub struct Library {
+    pub config: ScanConfig,
+    pub songs: Vec<ScannedSong>,
+    // roots as given and as reported by the file watcher
+    roots: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Library {
+    pub fn scan(config: ScanConfig) -> (Library, ScanReport) {
+        let (songs, report) = scanner::scan_sources(&config);
+        let roots = config
+            .roots
+            .iter()
+            .map(|r| (r.clone(), fs::canonicalize(r).unwrap_or_else(|_| r.clone())))
+            .collect();
+        let mut library = Library { config, songs, roots };
+        library.songs.sort_by(|a, b| a.source.cmp(&b.source));
+        (library, report)
+    }
+
+    pub fn song_entries(&self) -> Vec<SongEntry> {
+        self.songs.iter().map(|s| s.song.clone()).collect()
+    }
+
+    /*
+       the folder to scan again after p changed, with its root
+       that is p itself for folders and archives, otherwise the closest
+       existing folder above it
+    */
+    fn affected(&self, p: &Path) -> Option<(PathBuf, PathBuf)> {
+        // a broken pattern was already reported by the first scan
+        let ignore = self.config.ignore_set().ok()?;
+        let (root, rel) = self.roots.iter().find_map(|(root, canonical)| {
+            p.strip_prefix(canonical)
+                .or_else(|_| p.strip_prefix(root))
+                .ok()
+                .map(|rel| (root, rel))
+        })?;
+
+        let ignored = rel.ancestors().any(|a| {
+            let name = a.file_name().unwrap_or_default().to_string_lossy();
+            !a.as_os_str().is_empty() && ignore.matches(&name, &a.to_string_lossy().replace('\\', "/"))
+        });
+        if ignored {
+            return None;
+        }
+
+        let mut folder = root.join(rel);
+        let is_zip = folder.extension().is_some_and(|e| e.eq_ignore_ascii_case("zip"));
+        if !(folder.is_dir() || (is_zip && folder.is_file())) {
+            folder.pop();
+        }
+        while !folder.exists() && folder.starts_with(root) && &folder != root {
+            folder.pop();
+        }
+        Some((root.clone(), folder))
+    }
+
+    // drops the songs from every changed folder and scans those folders again
+    pub fn update(&mut self, changed: &[PathBuf]) -> ScanReport {
+        let mut folders: Vec<(PathBuf, PathBuf)> = changed.iter().filter_map(|p| self.affected(p)).collect();
+        folders.sort();
+        folders.dedup();
+
+        // a folder inside another one is already covered
+        let mut kept: Vec<(PathBuf, PathBuf)> = vec![];
+        for (root, folder) in folders {
+            if !kept.iter().any(|(_, k)| folder.starts_with(k)) {
+                kept.push((root, folder));
+            }
+        }
+
+        let mut report = ScanReport::default();
+        for (root, folder) in kept {
+            log::info!("rescanning {}", folder.display());
+            self.songs.retain(|s| !s.source.starts_with(&folder));
+            let (songs, r) = scanner::rescan(&self.config, &root, &[folder], &self.songs);
+            self.songs.extend(songs);
+            report.merge(r);
+        }
+        self.songs.sort_by(|a, b| a.source.cmp(&b.source));
+        report.songs = self.songs.len();
+        report
+    }
+}
+
+// absolute path of a file that might not exist yet
+fn absolute(p: &Path) -> PathBuf {
+    let dir = p.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    match (fs::canonicalize(dir), p.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => p.to_path_buf(),
+    }
+}
+
+/*
+   true for the output itself, anything inside it and the files next to it
+   named after it, like its temp file, cache backups and SQLite journals
+*/
+fn belongs_to(p: &Path, output: &Path) -> bool {
+    if p.starts_with(output) {
+        return true;
+    }
+    match (p.parent(), p.file_name(), output.parent(), output.file_name()) {
+        (Some(dir), Some(name), Some(out_dir), Some(out_name)) => {
+            dir == out_dir && name.to_string_lossy().starts_with(&*out_name.to_string_lossy())
+        }
+        _ => false,
+    }
+}
+
+/*
+   watches the roots of the library and updates it on every change
+   events are collected until nothing happened for the debounce time,
+   changes to the skipped paths and the files belonging to them, like the
+   written outputs, are not looked at, on_update gets the library after
+   every rescan
+*/
+pub fn watch(
+    mut library: Library,
+    debounce: Duration,
+    skip: &[PathBuf],
+    mut on_update: impl FnMut(&Library, &ScanReport),
+) -> Result<(), String> {
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx).map_err(|e| e.to_string())?;
+    for root in &library.config.roots {
+        watcher
+            .watch(root, RecursiveMode::Recursive)
+            .map_err(|e| format!("{}: {}", root.display(), e))?;
+    }
+    let skip: Vec<PathBuf> = skip.iter().map(|p| absolute(p)).collect();
+
+    let mut changed = vec![];
+    let collect = |event: notify::Result<Event>, changed: &mut Vec<PathBuf>| match event {
+        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+            changed.extend(event.paths.into_iter().filter(|p| !skip.iter().any(|s| belongs_to(p, s))));
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("watch: {}", e),
+    };
+
+    loop {
+        // wait for the first change, then until it is quiet again
+        match rx.recv() {
+            Ok(event) => collect(event, &mut changed),
+            Err(_) => return Ok(()),
+        }
+        while let Ok(event) = rx.recv_timeout(debounce) {
+            collect(event, &mut changed);
+        }
+        if changed.is_empty() {
+            continue;
+        }
+
+        changed.sort();
+        changed.dedup();
+        let report = library.update(&changed);
+        changed.clear();
+        on_update(&library, &report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs_own_their_side_files() {
+        let cache = Path::new("/songs/songcache.bin");
+        for p in ["songcache.bin", "songcache.bin.tmp", "songcache.bin.2", "songcache.bin.entries"] {
+            assert!(belongs_to(&Path::new("/songs").join(p), cache), "{}", p);
+        }
+        let db = Path::new("/songs/songs.db");
+        assert!(belongs_to(Path::new("/songs/songs.db-journal"), db));
+        assert!(belongs_to(Path::new("/songs/setlists/a.setlist"), Path::new("/songs/setlists")));
+
+        assert!(!belongs_to(Path::new("/songs/other/songcache.bin"), cache));
+        assert!(!belongs_to(Path::new("/songs/song.ini"), cache));
+    }
+}
@@ -1,106 +1,367 @@
 use std::io::prelude::*;
-use std::{io::Cursor, fs::File};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
 use byteorder::{LittleEndian, WriteBytesExt};
+use crate::intern::{StringTable, TableOrder};
 use crate::songentry::SongEntry;
-use crate::VERSION;
+use crate::{util, VERSION};
 
 // .NET 7 bit integer writer
-fn write_7_bit_int(value: i32, f: &mut File) {
+fn write_7_bit_int<W: Write>(value: i32, f: &mut W) -> io::Result<()> {
     let mut v = value as u32;
     while v >= 0x80 {
-        f.write_u8((v | 0x80) as u8).unwrap();
+        f.write_u8((v | 0x80) as u8)?;
         v >>= 7;
     }
-    f.write_u8(v as u8).unwrap();
+    f.write_u8(v as u8)
 }
 
 // .NET length prefixed string writer
-fn write_string(v: String, f: &mut File) {
+fn write_string<W: Write>(v: String, f: &mut W) -> io::Result<()> {
     let bytes = v.into_bytes();
-    write_7_bit_int(bytes.len() as i32, f);
-    f.write(&bytes).unwrap();
+    write_7_bit_int(bytes.len() as i32, f)?;
+    f.write_all(&bytes)
 }
 
 // .NET bool writer
-fn write_boolean(b: bool, f: &mut File) {
+fn write_boolean<W: Write>(b: bool, f: &mut W) -> io::Result<()> {
     if b {
-        f.write_u8(1).unwrap();
+        f.write_u8(1)
     } else {
-        f.write_u8(0).unwrap();
+        f.write_u8(0)
     }
 }
 
-pub fn write_cache(list: Vec<SongEntry>, f: &mut File) {
-    f.write_i32::<LittleEndian>(VERSION).unwrap();
+// one entry, indices point into the metadata tables
+fn write_entry<W: Write>(song: &SongEntry, indices: [i32; 7], f: &mut W) -> io::Result<()> {
+    if song.chart_name.len() > 250 {
+        log::warn!("chart_name of {} is {} bytes long", song.folder_path, song.chart_name.len());
+    }
+
+    write_string(song.folder_path.clone(), f)?;
+    f.write_i64::<LittleEndian>(0)?;
+    f.write_i64::<LittleEndian>(0)?;
+    write_string(song.chart_name.clone(), f)?;
+    write_boolean(song.is_enc, f)?;
+
+    for idx in indices {
+        f.write_i32::<LittleEndian>(idx)?;
+    }
+
+    f.write_i64::<LittleEndian>(song.charts.0)?;
+    write_boolean(song.lyrics, f)?;
+
+    f.write_i8(song.intensities.band)?;
+    f.write_i8(song.intensities.guitar)?;
+    f.write_i8(song.intensities.rhythm)?;
+    f.write_i8(song.intensities.bass)?;
+    f.write_i8(song.intensities.drums)?;
+    f.write_i8(song.intensities.pro_drums)?;
+    f.write_i8(song.intensities.keys)?;
+    f.write_i8(song.intensities.ghl_guitar)?;
+    f.write_i8(song.intensities.ghl_bass)?;
+
+    f.write_i32::<LittleEndian>(song.preview_start)?;
+
+    write_string(song.icon_name.clone(), f)?;
+    f.write_i16::<LittleEndian>(song.album_track)?;
+    f.write_i16::<LittleEndian>(song.playlist_track)?;
+    write_boolean(song.modchart, f)?;
+    write_boolean(song.video_background, f)?;
+    write_boolean(song.force_pro_drums, f)?;
+    write_boolean(song.force_five_lane, f)?;
+    f.write_i32::<LittleEndian>(song.song_length)?;
+    f.write_i64::<LittleEndian>(song.date_added)?;
+    write_string(song.top_level_playlist.clone(), f)?;
+    write_string(song.sub_playlist.clone(), f)?;
+    f.write_all(&song.checksum)?;
+    Ok(())
+}
+
+pub fn write_cache<W: Write>(list: Vec<SongEntry>, f: &mut W) -> io::Result<()> {
+    write_cache_ordered(list, TableOrder::Insertion, f)
+}
+
+pub fn write_cache_ordered<W: Write>(list: Vec<SongEntry>, order: TableOrder, f: &mut W) -> io::Result<()> {
+    let mut w = CacheWriter::with_tables(&list, order);
+    for song in &list {
+        w.add(song)?;
+    }
+    w.finish(f)
+}
+
+enum Body {
+    Memory(Vec<u8>),
+    Spill(PathBuf, BufWriter<File>),
+}
+
+/*
+   writes a cache one entry at a time
+   the header needs the metadata tables and the checksum of all entries,
+   so the entries are kept as encoded bytes, in memory or in a spill file,
+   until finish writes the header followed by them
+*/
+pub struct CacheWriter {
+    tables: [StringTable; 7],
+    checksum: md5::Context,
+    count: i32,
+    body: Body,
+}
+
+impl Default for CacheWriter {
+    fn default() -> Self {
+        CacheWriter::new()
+    }
+}
+
+impl CacheWriter {
+    pub fn new() -> CacheWriter {
+        CacheWriter {
+            tables: Default::default(),
+            checksum: md5::Context::new(),
+            count: 0,
+            body: Body::Memory(vec![]),
+        }
+    }
+
+    /*
+       starts with the metadata tables of every song in list already filled in,
+       in the given order, the entries still have to be added
+       a streaming writer can only keep the insertion order
+    */
+    pub fn with_tables(list: &[SongEntry], order: TableOrder) -> CacheWriter {
+        let mut w = CacheWriter::new();
+        for song in list {
+            for (table, m) in w.tables.iter_mut().zip(&song.metadata) {
+                table.intern(m);
+            }
+        }
+        for table in &mut w.tables {
+            table.order(order);
+        }
+        w
+    }
+
+    // keeps the encoded entries in a file at p instead of memory, it is removed afterwards
+    pub fn with_spill(p: &Path) -> io::Result<CacheWriter> {
+        let f = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(p)?;
+        let mut w = CacheWriter::new();
+        w.body = Body::Spill(p.to_path_buf(), BufWriter::new(f));
+        Ok(w)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn add(&mut self, song: &SongEntry) -> io::Result<()> {
+        let mut indices = [0; 7];
+        for (j, idx) in indices.iter_mut().enumerate() {
+            *idx = self.tables[j].intern(&song.metadata[j]);
+        }
+        match &mut self.body {
+            Body::Memory(b) => write_entry(song, indices, b)?,
+            Body::Spill(_, b) => write_entry(song, indices, b)?,
+        }
+        self.checksum.consume(song.checksum);
+        self.count += 1;
+        Ok(())
+    }
+
+    // writes the header, the tables and every added entry
+    pub fn finish<W: Write>(mut self, f: &mut W) -> io::Result<()> {
+        f.write_i32::<LittleEndian>(VERSION)?;
+        let checksum = std::mem::replace(&mut self.checksum, md5::Context::new());
+        f.write_all(&checksum.compute().0)?;
 
-    let mut checksum = Cursor::new(vec![0u8; list.len() * 16]);
-    let mut lists = [vec![], vec![], vec![], vec![], vec![], vec![], vec![]];
+        for (i, table) in self.tables.iter().enumerate() {
+            f.write_u8(i as u8)?;
+            f.write_i32::<LittleEndian>(table.len() as i32)?;
+            for s in table.strings() {
+                write_string(s.clone(), f)?;
+            }
+        }
 
-    for i in 0..list.len() {
-        checksum.write(&list[i].checksum).unwrap();
-        for j in 0..7 {
-            if !lists[j].contains(&list[i].metadata[j]) {
-                lists[j].push(list[i].metadata[j].clone());
+        f.write_i32::<LittleEndian>(self.count)?;
+        match &mut self.body {
+            Body::Memory(b) => f.write_all(b)?,
+            Body::Spill(_, b) => {
+                b.flush()?;
+                let spill = b.get_mut();
+                spill.seek(SeekFrom::Start(0))?;
+                io::copy(spill, f)?;
             }
         }
+        Ok(())
     }
 
-    let check = md5::compute(checksum.into_inner());
-    f.write(&check.0).unwrap();
+    // like save_cache, the finished cache replaces p in one step
+    pub fn save(self, p: &Path, backups: usize) -> io::Result<()> {
+        let tmp = util::temp_path(p);
+        let result = (|| {
+            let mut f = BufWriter::new(File::create(&tmp)?);
+            self.finish(&mut f)?;
+            f.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            rotate_backups(p, backups)?;
+            fs::rename(&tmp, p)
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result?;
+        sync_dir(p)
+    }
+}
 
-    for i in 0usize..7 {
-        f.write_u8(i as u8).unwrap();
-        let len = lists[i].len();
-        f.write_i32::<LittleEndian>(len as i32).unwrap();
-        for j in 0..len {
-            write_string(format!("{} hej", lists[i][j].clone()), f);
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if let Body::Spill(p, _) = &self.body {
+            let _ = fs::remove_file(p);
         }
     }
+}
 
-    f.write_i32::<LittleEndian>(list.len() as i32).unwrap();
-    
-    for i in 0..list.len() {
-        if list[i].chart_name.clone().as_bytes().len() > 250 {
-            println!("yeh, {:?}", list[i].folder_path);
+// songcache.bin.1 is the newest backup
+fn backup_path(p: &Path, n: usize) -> PathBuf {
+    let mut name = p.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", n));
+    p.with_file_name(name)
+}
+
+// shifts the backups up by one and copies the current file to the first one
+fn rotate_backups(p: &Path, backups: usize) -> io::Result<()> {
+    if backups == 0 || !p.exists() {
+        return Ok(());
+    }
+    for n in (1..backups).rev() {
+        let from = backup_path(p, n);
+        if from.exists() {
+            fs::rename(&from, backup_path(p, n + 1))?;
         }
-        
-        write_string(list[i].folder_path.clone(), f);
-        f.write_i64::<LittleEndian>(0).unwrap();
-        f.write_i64::<LittleEndian>(0).unwrap();
-        write_string(list[i].chart_name.clone(), f);
-        write_boolean(list[i].is_enc, f);
-
-        for j in 0..list[i].metadata.len() {
-            let idx = lists[j].iter().position(|x| x == &list[i].metadata[j]);
-            f.write_i32::<LittleEndian>(idx.unwrap() as i32).unwrap();
+    }
+    fs::copy(p, backup_path(p, 1))?;
+    Ok(())
+}
+
+/*
+   writes the cache to a temporary file next to p, syncs it to disk and
+   renames it over p, so p is always either the old or the new cache
+   with backups > 0 the previous cache is kept as p.1 up to p.<backups>
+*/
+pub fn save_cache(list: Vec<SongEntry>, p: &Path, backups: usize) -> io::Result<()> {
+    save_cache_ordered(list, p, backups, TableOrder::Insertion)
+}
+
+pub fn save_cache_ordered(list: Vec<SongEntry>, p: &Path, backups: usize, order: TableOrder) -> io::Result<()> {
+    let mut w = CacheWriter::with_tables(&list, order);
+    for song in &list {
+        w.add(song)?;
+    }
+    w.save(p, backups)
+}
+
+// makes a rename in the folder of p survive a crash
+fn sync_dir(p: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(dir) = p.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::instrument::{Difficulty, Instrument};
+    use crate::reader;
+    use std::io::Cursor;
+
+    // a few songs that share some metadata, with long strings and a file list
+    pub(crate) fn songs() -> Vec<SongEntry> {
+        (0..5u8)
+            .map(|i| {
+                let mut song = SongEntry {
+                    folder_path: format!("/songs/{}/{}", "x".repeat(200), i),
+                    chart_name: format!("notes.chart\nsong.ini\nalbum.png\n{}", i),
+                    checksum: [i + 1; 16],
+                    metadata: [
+                        format!("Song {}", i),
+                        String::from(["ACDC", "Dragonforce"][i as usize % 2]),
+                        String::from("Album"),
+                        String::from(["Rock", "Metal", "Pop"][i as usize % 3]),
+                        String::from("2001"),
+                        String::from("Charter"),
+                        String::from(["rock", "metal"][i as usize % 2]),
+                    ],
+                    song_length: 1000 * i as i32,
+                    lyrics: i % 2 == 0,
+                    ..Default::default()
+                };
+                song.charts.insert(Instrument::Guitar, Difficulty::Expert);
+                song.intensities.guitar = i as i8;
+                song
+            })
+            .collect()
+    }
+
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("cloud-hero-test-{}-{}", std::process::id(), name))
+    }
+
+    fn buffered(songs: Vec<SongEntry>) -> Vec<u8> {
+        let mut out = vec![];
+        write_cache(songs, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn streaming_matches_buffered() {
+        let spill = temp_path("spill");
+        let mut w = CacheWriter::with_spill(&spill).unwrap();
+        for song in &songs() {
+            w.add(song).unwrap();
         }
+        assert_eq!(w.len(), 5);
+        let mut streamed = vec![];
+        w.finish(&mut streamed).unwrap();
+
+        assert_eq!(streamed, buffered(songs()));
+        assert!(!spill.exists());
+    }
+
+    #[test]
+    fn tables_keep_first_seen_order() {
+        let data = buffered(songs());
+        let cache = reader::IndexedCache::new(Cursor::new(data)).unwrap();
+        let lists = &cache.header().lists;
+        assert_eq!(lists[1], ["ACDC", "Dragonforce"]);
+        assert_eq!(lists[3], ["Rock", "Metal", "Pop"]);
+        assert_eq!(lists[0].len(), 5);
+    }
+
+    #[test]
+    fn empty_cache_has_empty_tables() {
+        let data = buffered(vec![]);
+        let cache = reader::IndexedCache::new(Cursor::new(data)).unwrap();
+        assert!(cache.is_empty());
+        assert!(cache.header().lists.iter().all(|l| l.is_empty()));
+    }
+
+    #[test]
+    fn sorted_tables_keep_the_songs() {
+        let mut data = vec![];
+        write_cache_ordered(songs(), TableOrder::Sorted, &mut data).unwrap();
+        let cache = reader::IndexedCache::new(Cursor::new(&data)).unwrap();
+        assert_eq!(cache.header().lists[3], ["Metal", "Pop", "Rock"]);
 
-        f.write_i64::<LittleEndian>(list[i].charts).unwrap();
-        write_boolean(list[i].lyrics, f);
-
-        f.write_i8(list[i].intensities[8]).unwrap();
-        f.write_i8(list[i].intensities[0]).unwrap();
-        f.write_i8(list[i].intensities[2]).unwrap();
-        f.write_i8(list[i].intensities[1]).unwrap();
-        f.write_i8(list[i].intensities[6]).unwrap();
-        f.write_i8(list[i].intensities[9]).unwrap();
-        f.write_i8(list[i].intensities[7]).unwrap();
-        f.write_i8(list[i].intensities[4]).unwrap();
-        f.write_i8(list[i].intensities[5]).unwrap();
-
-        f.write_i32::<LittleEndian>(list[i].preview_start).unwrap();
-
-        write_string(list[i].icon_name.clone(), f);
-        f.write_i16::<LittleEndian>(list[i].album_track).unwrap();
-        f.write_i16::<LittleEndian>(list[i].playlist_track).unwrap();
-        write_boolean(list[i].modchart, f);
-        write_boolean(list[i].video_background, f);
-        write_boolean(list[i].force_pro_drums, f);
-        write_boolean(list[i].force_five_lane, f);
-        f.write_i32::<LittleEndian>(list[i].song_length).unwrap();
-        f.write_i64::<LittleEndian>(list[i].date_added).unwrap();
-        write_string(list[i].top_level_playlist.clone(), f);
-        write_string(list[i].sub_playlist.clone(), f);
-        f.write(&list[i].checksum).unwrap();
+        let read = reader::read_cache(&mut Cursor::new(&data)).unwrap();
+        let metadata: Vec<_> = read.iter().map(|s| s.metadata.clone()).collect();
+        let expected: Vec<_> = songs().iter().map(|s| s.metadata.clone()).collect();
+        assert_eq!(metadata, expected);
     }
 }